@@ -0,0 +1,308 @@
+use crate::util::dna::DnaStrand;
+use crate::util::file::read_lines;
+use crate::util::gff::parse;
+
+use bio::data_structures::interval_tree::ArrayBackedIntervalTree;
+use std::collections::HashMap;
+
+/// A transcript model built from exon, CDS and stop codon records in
+/// a GFF file
+///
+pub struct Transcript {
+    pub gene: Box<str>,
+    pub transcript: Box<str>,
+    pub chr: Box<str>,
+    pub strand: DnaStrand,
+    /// 0-based, half-open exon intervals sorted by start
+    pub exons: Vec<(i64, i64)>,
+    /// the genomic span of coding sequences, including the stop codon
+    /// (0-based, half-open)
+    pub cds: Option<(i64, i64)>,
+}
+
+/// Where a site falls within a transcript
+///
+pub struct SiteAnnotation<'a> {
+    pub tx: &'a Transcript,
+    /// 5'UTR, CDS, 3'UTR, ncRNA or intron
+    pub region: &'static str,
+    /// 5'UTR in [0, 1), CDS in [1, 2), 3'UTR in [2, 3]
+    pub metagene: Option<f32>,
+    /// distance to the end of CDS in the spliced transcript,
+    /// negative upstream and non-negative downstream
+    pub dist_to_stop: Option<i64>,
+}
+
+#[allow(dead_code)]
+impl Transcript {
+    pub fn start(&self) -> i64 {
+        self.exons.first().map(|x| x.0).unwrap_or(0)
+    }
+
+    pub fn end(&self) -> i64 {
+        self.exons.last().map(|x| x.1).unwrap_or(0)
+    }
+
+    pub fn len(&self) -> i64 {
+        self.exons.iter().map(|(s, e)| e - s).sum()
+    }
+
+    /// Map a genomic position to 0-based position in the spliced
+    /// transcript, from 5' to 3'.  `None` if it falls outside exons.
+    pub fn to_tx_position(&self, gpos: i64) -> Option<i64> {
+        let mut offset = 0;
+        let mut found = None;
+        for &(s, e) in self.exons.iter() {
+            if s <= gpos && gpos < e {
+                found = Some(offset + gpos - s);
+            }
+            offset += e - s;
+        }
+        match self.strand {
+            DnaStrand::Forward => found,
+            DnaStrand::Reverse => found.map(|x| offset - 1 - x),
+        }
+    }
+
+    /// Locate a genomic position relative to UTRs and CDS
+    pub fn annotate(&self, gpos: i64) -> SiteAnnotation<'_> {
+        let Some(tx_pos) = self.to_tx_position(gpos) else {
+            return SiteAnnotation {
+                tx: self,
+                region: "intron",
+                metagene: None,
+                dist_to_stop: None,
+            };
+        };
+
+        // CDS boundaries in transcript coordinates, [start, stop)
+        let cds = self.cds.and_then(|(lb, ub)| {
+            let a = self.to_tx_position(lb)?;
+            let b = self.to_tx_position(ub - 1)?;
+            Some((a.min(b), a.max(b) + 1))
+        });
+
+        let Some((cds_start, cds_stop)) = cds else {
+            return SiteAnnotation {
+                tx: self,
+                region: "ncRNA",
+                metagene: None,
+                dist_to_stop: None,
+            };
+        };
+
+        let tx_len = self.len();
+        let frac = |x: i64, lb: i64, ub: i64| (x - lb) as f32 / (ub - lb).max(1) as f32;
+
+        let (region, metagene) = if tx_pos < cds_start {
+            ("5'UTR", frac(tx_pos, 0, cds_start))
+        } else if tx_pos < cds_stop {
+            ("CDS", 1_f32 + frac(tx_pos, cds_start, cds_stop))
+        } else {
+            ("3'UTR", 2_f32 + frac(tx_pos, cds_stop, tx_len))
+        };
+
+        SiteAnnotation {
+            tx: self,
+            region,
+            metagene: Some(metagene),
+            dist_to_stop: Some(tx_pos - cds_stop),
+        }
+    }
+}
+
+/// Transcript models indexed by chromosome for overlap queries
+///
+pub struct TranscriptIndex {
+    transcripts: Vec<Transcript>,
+    trees: HashMap<Box<str>, ArrayBackedIntervalTree<i64, usize>>,
+}
+
+impl TranscriptIndex {
+    /// Build transcript models from `exon`, `CDS` and `stop_codon`
+    /// records.  GTF files leave the stop codon out of `CDS`, so it is
+    /// added back to end the CDS at the same place as in GFF3.
+    ///
+    /// * `gff_file` - GFF/GTF file name
+    /// * `gene_key` - attribute for gene IDs
+    /// * `transcript_key` - attribute for transcript IDs (falling
+    ///   back to `Parent`)
+    ///
+    pub fn from_gff(gff_file: &str, gene_key: &str, transcript_key: &str) -> anyhow::Result<Self> {
+        let mut models: HashMap<Box<str>, Transcript> = HashMap::new();
+
+        for line in read_lines(gff_file)? {
+            if line.starts_with('#') {
+                continue;
+            }
+            let Some(rec) = parse(line) else {
+                continue;
+            };
+            let is_cds = match rec.feature_type() {
+                "exon" => false,
+                "CDS" | "stop_codon" => true,
+                _ => continue,
+            };
+            let Some(tx) = [transcript_key, "Parent"]
+                .iter()
+                .find_map(|k| rec.attributes().get(*k))
+            else {
+                continue;
+            };
+            let strand = match rec.strand().as_ref().map(|x| x.strand_symbol()) {
+                Some("-") => DnaStrand::Reverse,
+                _ => DnaStrand::Forward,
+            };
+            let (lb, ub) = ((*rec.start() as i64 - 1).max(0), *rec.end() as i64);
+
+            let model = models
+                .entry(tx.as_str().into())
+                .or_insert_with(|| Transcript {
+                    gene: rec
+                        .attributes()
+                        .get(gene_key)
+                        .map(|x| x.as_str())
+                        .unwrap_or(".")
+                        .into(),
+                    transcript: tx.as_str().into(),
+                    chr: rec.seqname().into(),
+                    strand,
+                    exons: vec![],
+                    cds: None,
+                });
+
+            if is_cds {
+                model.cds = Some(match model.cds {
+                    Some((s, e)) => (s.min(lb), e.max(ub)),
+                    None => (lb, ub),
+                });
+            } else {
+                model.exons.push((lb, ub));
+            }
+        }
+
+        let mut transcripts: Vec<Transcript> = models
+            .into_values()
+            .filter(|x| !x.exons.is_empty())
+            .map(|mut x| {
+                x.exons.sort();
+                x
+            })
+            .collect();
+
+        transcripts.sort_by(|a, b| (&a.chr, a.start()).cmp(&(&b.chr, b.start())));
+
+        let mut trees: HashMap<Box<str>, ArrayBackedIntervalTree<i64, usize>> = HashMap::new();
+        for (i, tx) in transcripts.iter().enumerate() {
+            trees
+                .entry(tx.chr.clone())
+                .or_default()
+                .insert(tx.start()..tx.end(), i);
+        }
+        for tree in trees.values_mut() {
+            tree.index();
+        }
+
+        Ok(TranscriptIndex { transcripts, trees })
+    }
+
+    pub fn len(&self) -> usize {
+        self.transcripts.len()
+    }
+
+    /// Annotate a genomic position with all the overlapping
+    /// transcripts
+    pub fn annotate(&self, chr: &str, gpos: i64) -> Vec<SiteAnnotation<'_>> {
+        let Some(tree) = self.trees.get(chr) else {
+            return vec![];
+        };
+        tree.find(gpos..(gpos + 1))
+            .iter()
+            .map(|x| self.transcripts[*x.data()].annotate(gpos))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// exons [100, 110) and [120, 140), 30 bases when spliced
+    fn two_exon_transcript(strand: DnaStrand, cds: (i64, i64)) -> Transcript {
+        Transcript {
+            gene: "g".into(),
+            transcript: "t".into(),
+            chr: "chr1".into(),
+            strand,
+            exons: vec![(100, 110), (120, 140)],
+            cds: Some(cds),
+        }
+    }
+
+    #[test]
+    fn annotate_forward_strand() {
+        // CDS ends with the stop codon at [127, 130), i.e., [5, 20)
+        // in the transcript
+        let tx = two_exon_transcript(DnaStrand::Forward, (105, 130));
+
+        assert_eq!(tx.to_tx_position(100), Some(0));
+        assert_eq!(tx.to_tx_position(120), Some(10));
+        assert_eq!(tx.to_tx_position(139), Some(29));
+        assert_eq!(tx.to_tx_position(115), None);
+
+        let utr5 = tx.annotate(102);
+        assert_eq!(utr5.region, "5'UTR");
+        assert_eq!(utr5.metagene, Some(0.4));
+        assert_eq!(utr5.dist_to_stop, Some(-18));
+
+        let cds = tx.annotate(107);
+        assert_eq!(cds.region, "CDS");
+        assert_eq!(cds.metagene, Some(1_f32 + 2_f32 / 15_f32));
+
+        let last = tx.annotate(129);
+        assert_eq!(last.region, "CDS");
+        assert_eq!(last.dist_to_stop, Some(-1));
+
+        let utr3 = tx.annotate(130);
+        assert_eq!(utr3.region, "3'UTR");
+        assert_eq!(utr3.metagene, Some(2_f32));
+        assert_eq!(utr3.dist_to_stop, Some(0));
+
+        let intron = tx.annotate(115);
+        assert_eq!(intron.region, "intron");
+        assert_eq!(intron.metagene, None);
+        assert_eq!(intron.dist_to_stop, None);
+    }
+
+    #[test]
+    fn annotate_reverse_strand() {
+        // CDS ends with the stop codon at [108, 111), i.e., [15, 22)
+        // in the transcript read from 139 down to 100
+        let tx = two_exon_transcript(DnaStrand::Reverse, (108, 125));
+
+        assert_eq!(tx.to_tx_position(139), Some(0));
+        assert_eq!(tx.to_tx_position(120), Some(19));
+        assert_eq!(tx.to_tx_position(100), Some(29));
+        assert_eq!(tx.to_tx_position(115), None);
+
+        let utr5 = tx.annotate(135);
+        assert_eq!(utr5.region, "5'UTR");
+        assert_eq!(utr5.metagene, Some(4_f32 / 15_f32));
+        assert_eq!(utr5.dist_to_stop, Some(-18));
+
+        let cds = tx.annotate(121);
+        assert_eq!(cds.region, "CDS");
+        assert_eq!(cds.metagene, Some(1_f32 + 3_f32 / 7_f32));
+
+        let last = tx.annotate(108);
+        assert_eq!(last.region, "CDS");
+        assert_eq!(last.dist_to_stop, Some(-1));
+
+        let utr3 = tx.annotate(107);
+        assert_eq!(utr3.region, "3'UTR");
+        assert_eq!(utr3.metagene, Some(2_f32));
+        assert_eq!(utr3.dist_to_stop, Some(0));
+
+        assert_eq!(tx.annotate(115).region, "intron");
+    }
+}
@@ -1,16 +1,212 @@
-use anyhow;
+pub mod annot;
+
+use crate::util::bam::*;
+use crate::util::dna::*;
+use crate::util::error::{is_faba_error, FabaError};
+use crate::util::file::{read_lines, write_lines};
+use annot::TranscriptIndex;
+
 use clap::Args;
+use rayon::prelude::*;
+use std::collections::BTreeSet;
 
 #[derive(Args)]
 pub struct AggArgs {
+    /// BAM or CRAM file
+    bam_file: Box<str>,
+    /// Candidate sites (`faba-cli sift` output with chr, pos, strand)
+    #[arg(short, long)]
+    sites: Box<str>,
     /// GFF file
     #[arg(short, long)]
     gff: Box<str>,
+    #[command(flatten)]
+    read_filters: ReadFilters,
+    #[command(flatten)]
+    index_options: IndexOptions,
+    /// Reference FASTA file to decode CRAM files
+    #[arg(short, long)]
+    reference: Option<Box<str>>,
+    /// GFF attribute for gene IDs
+    #[arg(long, default_value = "gene_id")]
+    gene_key: Box<str>,
+    /// GFF attribute for transcript IDs
+    #[arg(long, default_value = "transcript_id")]
+    transcript_key: Box<str>,
+    /// Genomic block size for fetching nearby sites at once
+    #[arg(long, default_value_t = 10_000)]
+    block_size: i64,
     /// Output file header
     #[arg(short, long)]
     output: Box<str>,
 }
 
+/// Revisit all the candidate sites, regardless of significance
+/// levels, to collect per-barcode base counts and locate them within
+/// the overlapping transcripts.
+///
 pub fn run_agg(args: &AggArgs) -> anyhow::Result<()> {
+    let sites = read_sites(&args.sites)?;
+    println!("Read {} sites from {}", sites.len(), args.sites);
+
+    let tx_index = TranscriptIndex::from_gff(&args.gff, &args.gene_key, &args.transcript_key)?;
+    println!("Read {} transcripts from {}", tx_index.len(), args.gff);
+
+    let bam_file = args.bam_file.as_ref();
+    check_cram_reference(bam_file, args.reference.as_deref())?;
+    let index_file = check_bam_index(bam_file, None, &args.index_options)?;
+    let mut bam_readers = BamReaderPool::new(bam_file, &index_file)?;
+    if let Some(fasta_file) = args.reference.as_deref() {
+        bam_readers.set_reference(fasta_file)?;
+    }
+
+    println!("Collecting statistics");
+
+    let blocks = group_sites(&sites, args.block_size);
+
+    let stat_lines: Vec<Vec<Box<str>>> = blocks
+        .par_iter()
+        .map(|block| -> anyhow::Result<Vec<Box<str>>> {
+            let mut lines = vec![];
+            let chr = block[0].0.as_ref();
+            let positions: Vec<i64> = block.iter().map(|(_, pos, _)| *pos).collect();
+            let region = (chr, positions[0], positions[positions.len() - 1] + 1);
+            let freq_map = bam_readers.with_reader(|bam_reader| {
+                get_dna_base_freq_at(bam_reader, region, &positions, &args.read_filters)
+            });
+            let freq_map = match freq_map {
+                Ok(freq_map) => freq_map,
+                Err(e) if is_faba_error(&e, &FabaError::EmptyRegion) => return Ok(lines),
+                Err(e) => return Err(e),
+            };
+            for (chr, pos, strand) in block.iter() {
+                for samp in freq_map.samples() {
+                    let stats = match strand {
+                        DnaStrand::Forward => freq_map.get_forward(samp),
+                        DnaStrand::Reverse => freq_map.get_reverse(samp),
+                    };
+                    let Some(stats) = stats else {
+                        continue;
+                    };
+                    let Ok(i) = stats.binary_search_by_key(pos, |bs| bs.position()) else {
+                        continue;
+                    };
+                    let bs = &stats[i];
+                    if bs.total() == 0_f32 {
+                        continue;
+                    }
+                    lines.push(
+                        format!(
+                            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                            chr,
+                            pos + 1,
+                            strand,
+                            samp,
+                            bs.get(Dna::A),
+                            bs.get(Dna::T),
+                            bs.get(Dna::G),
+                            bs.get(Dna::C)
+                        )
+                        .into_boxed_str(),
+                    );
+                }
+            }
+            Ok(lines)
+        })
+        .collect::<anyhow::Result<_>>()?;
+
+    let mut lines: Vec<Box<str>> = vec!["chr\tpos\tstrand\tsample\tA\tT\tG\tC".into()];
+    lines.extend(stat_lines.into_iter().flatten());
+    write_lines(&lines, &format!("{}.site_stats.tsv.gz", args.output))?;
+
+    let mut lines: Vec<Box<str>> = vec![
+        "chr\tpos\tstrand\tgene\ttranscript\ttx_strand\tregion\tmetagene\tdist_to_stop".into(),
+    ];
+
+    let na = || "NA".to_string();
+    for (chr, pos, strand) in sites.iter() {
+        for annot in tx_index.annotate(chr, *pos) {
+            lines.push(
+                format!(
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    chr,
+                    pos + 1,
+                    strand,
+                    annot.tx.gene,
+                    annot.tx.transcript,
+                    annot.tx.strand,
+                    annot.region,
+                    annot.metagene.map(|x| x.to_string()).unwrap_or_else(na),
+                    annot.dist_to_stop.map(|x| x.to_string()).unwrap_or_else(na)
+                )
+                .into_boxed_str(),
+            );
+        }
+    }
+    write_lines(&lines, &format!("{}.site_annot.tsv.gz", args.output))?;
+
+    println!(
+        "Wrote {}.site_stats.tsv.gz and {}.site_annot.tsv.gz",
+        args.output, args.output
+    );
     Ok(())
 }
+
+/// Group the sorted sites into blocks of the same contig spanning
+/// less than `block_size` bases, so that each block is fetched once
+///
+fn group_sites(
+    sites: &[(Box<str>, i64, DnaStrand)],
+    block_size: i64,
+) -> Vec<&[(Box<str>, i64, DnaStrand)]> {
+    let mut blocks = vec![];
+    let mut start = 0;
+    for i in 1..=sites.len() {
+        let (chr, lb, _) = &sites[start];
+        if i == sites.len() || sites[i].0 != *chr || sites[i].1 >= lb + block_size {
+            blocks.push(&sites[start..i]);
+            start = i;
+        }
+    }
+    blocks
+}
+
+/// Read unique (chr, 0-based pos, strand) sites from a tab-separated
+/// file with `chr`, `pos` (1-based) and `strand` columns.  Without a
+/// header line, the first three columns are taken.
+///
+fn read_sites(sites_file: &str) -> anyhow::Result<Vec<(Box<str>, i64, DnaStrand)>> {
+    let lines = read_lines(sites_file)?;
+
+    let mut columns = [0, 1, 2];
+    let mut skip = 0;
+
+    if let Some(header) = lines.first() {
+        let words: Vec<&str> = header.split('\t').collect();
+        let find = |name: &str| words.iter().position(|w| *w == name);
+        if let (Some(c), Some(p), Some(s)) = (find("chr"), find("pos"), find("strand")) {
+            columns = [c, p, s];
+            skip = 1;
+        }
+    }
+
+    let mut sites = BTreeSet::new();
+
+    for line in lines.iter().skip(skip) {
+        let words: Vec<&str> = line.split('\t').collect();
+        let (Some(chr), Some(pos), Some(strand)) = (
+            words.get(columns[0]),
+            words.get(columns[1]).and_then(|x| x.parse::<i64>().ok()),
+            words.get(columns[2]),
+        ) else {
+            continue;
+        };
+        let strand = match *strand {
+            "-" => DnaStrand::Reverse,
+            _ => DnaStrand::Forward,
+        };
+        sites.insert((Box::<str>::from(*chr), pos - 1, strand));
+    }
+
+    Ok(sites.into_iter().collect())
+}
@@ -0,0 +1,34 @@
+use crate::util::bam::{check_bam_index, check_cram_reference, BamReaderPool};
+use crate::util::strandedness::infer_library_type;
+
+/// Infer the library type from reads over GFF exons and print out
+/// how read orientations agree with gene strands.
+///
+pub fn run_infer(args: &super::InferArgs) -> anyhow::Result<()> {
+    let bam_file = args.bam_file.as_ref();
+    check_cram_reference(bam_file, args.reference.as_deref())?;
+    let index_file = check_bam_index(bam_file, None, &args.index_options)?;
+    let mut bam_readers = BamReaderPool::new(bam_file, &index_file)?;
+    if let Some(fasta_file) = args.reference.as_deref() {
+        bam_readers.set_reference(fasta_file)?;
+    }
+
+    let report = infer_library_type(
+        &bam_readers,
+        &args.gff,
+        &args.feature_types,
+        &args.read_filters,
+        args.max_reads,
+        args.min_agreement,
+    )?;
+
+    println!("mate\tread_strand\tgene_strand\tcount");
+    for (mate, read_strand, gene_strand, n) in report.count_table() {
+        println!("{}\t{}\t{}\t{}", mate, read_strand, gene_strand, n);
+    }
+    println!(
+        "Library type: {} (agreement {:.4} over {} reads, {:.4} with barcodes)",
+        report.library_type, report.agreement, report.nreads, report.barcoded
+    );
+    Ok(())
+}
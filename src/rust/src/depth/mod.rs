@@ -1,13 +1,61 @@
+pub mod infer;
 pub mod run;
 
+use crate::util::bam::{IndexOptions, ReadFilters};
+use crate::util::strandedness::{DEFAULT_MAX_READS, DEFAULT_MIN_AGREEMENT};
+
 use clap::Args;
 
 #[derive(Args)]
 pub struct DepthArgs {
+    /// BAM or CRAM file
+    bam_file: Box<str>,
     /// GFF file
     #[arg(short, long)]
     gff: Box<str>,
+    /// GFF feature types to count reads on
+    #[arg(short = 't', long, value_delimiter = ',', default_value = "exon")]
+    feature_types: Vec<Box<str>>,
+    /// GFF attribute to name (and group) features
+    #[arg(short, long, default_value = "gene_id")]
+    name_key: Box<str>,
+    #[command(flatten)]
+    read_filters: ReadFilters,
+    #[command(flatten)]
+    index_options: IndexOptions,
+    /// Reference FASTA file to decode CRAM files
+    #[arg(short, long)]
+    reference: Option<Box<str>>,
+    /// Split read counts by cell barcodes
+    #[arg(long, default_value_t = false)]
+    barcode: bool,
     /// Output file header
     #[arg(short, long)]
     output: Box<str>,
 }
+
+#[derive(Args)]
+pub struct InferArgs {
+    /// BAM or CRAM file
+    bam_file: Box<str>,
+    /// GFF file
+    #[arg(short, long)]
+    gff: Box<str>,
+    /// GFF feature types to take gene strands from
+    #[arg(short = 't', long, value_delimiter = ',', default_value = "exon")]
+    feature_types: Vec<Box<str>>,
+    #[command(flatten)]
+    read_filters: ReadFilters,
+    #[command(flatten)]
+    index_options: IndexOptions,
+    /// Reference FASTA file to decode CRAM files
+    #[arg(short, long)]
+    reference: Option<Box<str>>,
+    /// Number of reads to sample
+    #[arg(long, default_value_t = DEFAULT_MAX_READS)]
+    max_reads: usize,
+    /// Minimum fraction of reads agreeing with gene strands to call a
+    /// stranded library
+    #[arg(long, default_value_t = DEFAULT_MIN_AGREEMENT)]
+    min_agreement: f32,
+}
@@ -1,7 +1,186 @@
-use anyhow;
+use crate::util::bam::*;
+use crate::util::error::FabaError;
+use crate::util::file::write_lines;
+use crate::util::gff::{read_gff_features, GffFeature};
+use crate::util::mtx::write_mtx;
 
-#[allow(dead_code)]
+use rayon::prelude::*;
+use rust_htslib::bam::{self, ext::BamRecordExtensions, Read};
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+/// Count strand-specific reads on each GFF feature and write out
+/// sparse feature x barcode matrices for the forward and reverse
+/// strands.
+///
 pub fn run_depth(args: &super::DepthArgs) -> anyhow::Result<()> {
+    let bam_file = args.bam_file.as_ref();
+    check_cram_reference(bam_file, args.reference.as_deref())?;
+    let index_file = check_bam_index(bam_file, None, &args.index_options)?;
+
+    let features = read_gff_features(&args.gff, &args.feature_types, &args.name_key)?;
+    println!("Read {} features from {}", features.len(), args.gff);
+
+    // annotations may have more contigs than the alignments, e.g., chrM
+    // or scaffolds missing in a chromosomes-only BAM
+    let contigs: HashSet<Box<str>> = read_contigs(bam_file)?
+        .into_iter()
+        .map(|(chr, _)| chr)
+        .collect();
+    let (features, dropped): (Vec<GffFeature>, Vec<GffFeature>) =
+        features.into_iter().partition(|f| contigs.contains(&f.chr));
+    if !dropped.is_empty() {
+        let unknown: BTreeSet<&str> = dropped.iter().map(|f| f.chr.as_ref()).collect();
+        eprintln!(
+            "warning: skipping {} features on contigs not found in {}: {}",
+            dropped.len(),
+            bam_file,
+            unknown.into_iter().collect::<Vec<_>>().join(",")
+        );
+    }
+
+    let mut bam_readers = BamReaderPool::new(bam_file, &index_file)?;
+    if let Some(fasta_file) = args.reference.as_deref() {
+        bam_readers.set_reference(fasta_file)?;
+    }
+
+    let depths: Vec<HashMap<BamSample, [f32; 2]>> = features
+        .par_iter()
+        .map(|f| {
+            bam_readers.with_reader(|bam_reader| {
+                count_feature_depth(bam_reader, f, &args.read_filters, args.barcode)
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let samples: Vec<BamSample> = depths
+        .iter()
+        .flat_map(|x| x.keys().cloned())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+
+    let samp2col: HashMap<&BamSample, usize> =
+        samples.iter().enumerate().map(|(j, s)| (s, j)).collect();
+
+    let mut forward = vec![];
+    let mut reverse = vec![];
+
+    for (i, depth) in depths.iter().enumerate() {
+        let mut row: Vec<(usize, [f32; 2])> =
+            depth.iter().map(|(s, x)| (samp2col[s], *x)).collect();
+        row.sort_by_key(|x| x.0);
+        for (j, [f, r]) in row {
+            if f > 0_f32 {
+                forward.push((i, j, f));
+            }
+            if r > 0_f32 {
+                reverse.push((i, j, r));
+            }
+        }
+    }
+
+    let (nrow, ncol) = (features.len(), samples.len());
+    write_mtx(
+        &forward,
+        nrow,
+        ncol,
+        &format!("{}.forward.mtx.gz", args.output),
+    )?;
+    write_mtx(
+        &reverse,
+        nrow,
+        ncol,
+        &format!("{}.reverse.mtx.gz", args.output),
+    )?;
+
+    let feature_lines: Vec<Box<str>> = features
+        .iter()
+        .map(|f| {
+            let strand = f.strand.map(|s| s.to_string()).unwrap_or(".".to_string());
+            format!(
+                "{}\t{}\t{}\t{}\t{}",
+                f.name,
+                f.chr,
+                f.start() + 1,
+                f.end(),
+                strand
+            )
+            .into_boxed_str()
+        })
+        .collect();
+    write_lines(&feature_lines, &format!("{}.features.tsv.gz", args.output))?;
+
+    let barcode_lines: Vec<Box<str>> = samples
+        .iter()
+        .map(|s| s.to_string().into_boxed_str())
+        .collect();
+    write_lines(&barcode_lines, &format!("{}.barcodes.tsv.gz", args.output))?;
+
+    println!(
+        "Wrote {} x {} depth matrices to {}.*.mtx.gz",
+        nrow, ncol, args.output
+    );
     Ok(())
 }
 
+/// Count reads aligned on a feature, split by sample and strand
+/// (forward, reverse).  A read is counted once even if it spans
+/// multiple intervals of the feature.  Features without reads are
+/// empty, but unknown contigs and unreadable records are errors.
+///
+/// * `bam_reader` - indexed BAM reader
+/// * `feature` - a GFF feature
+/// * `read_filters` - filters on reads
+/// * `by_barcode` - split the counts by 10x cell barcodes
+///
+fn count_feature_depth(
+    bam_reader: &mut bam::IndexedReader,
+    feature: &GffFeature,
+    read_filters: &ReadFilters,
+    by_barcode: bool,
+) -> anyhow::Result<HashMap<BamSample, [f32; 2]>> {
+    let mut ret: HashMap<BamSample, [f32; 2]> = HashMap::new();
+    let mut visited: HashSet<(Vec<u8>, bool)> = HashSet::new();
+
+    let chr = feature.chr.as_ref();
+    if bam_reader.header().tid(chr.as_bytes()).is_none() {
+        return Err(FabaError::UnknownContig(chr.into()).into());
+    }
+
+    for &(lb, ub) in feature.intervals.iter() {
+        bam_reader
+            .fetch((chr, lb, ub))
+            .map_err(|e| anyhow::anyhow!("unable to fetch {}:{}-{}: {}", chr, lb, ub, e))?;
+
+        for rec in bam_reader.records() {
+            let rec = rec?;
+            if !read_filters.pass_read(&rec) {
+                continue;
+            }
+
+            if !rec.aligned_blocks().any(|[s, e]| feature.overlaps(s, e)) {
+                continue;
+            }
+
+            if !visited.insert((rec.qname().to_vec(), rec.is_last_in_template())) {
+                continue;
+            }
+
+            let Some(sample_id) = read_filters.sample_of(&rec) else {
+                continue;
+            };
+            let sample_id = match by_barcode {
+                true => sample_id,
+                _ => BamSample::Combined,
+            };
+
+            let depth = ret.entry(sample_id).or_insert([0_f32; 2]);
+            match read_filters.library_type.is_reverse(&rec) {
+                true => depth[1] += 1_f32,
+                _ => depth[0] += 1_f32,
+            }
+        }
+    }
+
+    Ok(ret)
+}
@@ -5,52 +5,487 @@ use extendr_api::prelude::*;
 mod sift;
 mod util;
 
-use crate::util::bam::check_bam_index;
+use crate::sift::compare::SiftConfig;
+use crate::sift::matrix::SiteMatrices;
+use crate::sift::report::{group_sites, CaseControlSite};
+use crate::sift::rules::ConversionRules;
+use crate::sift::test::{test_case_control_sites, SiteTest, SiteTestResult};
+use crate::util::bam::{
+    check_bam_index, check_cram_reference, BamReaderPool, IndexOptions, IndexType, LibraryType,
+    ReadFilters, StaleIndex, Whitelist,
+};
+use crate::util::dna::Dna;
+use crate::util::mtx::triplets_to_csc;
+use crate::util::regions::RegionFilters;
+use crate::util::strandedness;
+
+use clap::ValueEnum;
 
 /// Sift through BAM records to identify potential variant sites.  The
 /// resulting sites may not be necessarily true hits, but they can
 /// form a good starting candidate pool.  Later, routines in
 /// `aggregate` could revisit them all, regardless of significance
-/// levels, and collect sufficient statistics for further tests.  So,
-/// we just need to return a vector of (chr: Box<str>, lb: i64, ub:
-/// i64)
+/// levels, and collect sufficient statistics for further tests.
+///
+/// Returns a long-format data frame with one row per (chr, pos,
+/// strand, sample), where `pos` is 1-based, `sample` is a cell
+/// barcode or `.` for reads without a cell barcode, `found_on` tells
+/// whether the position was variable on the forward (`+`), reverse
+/// (`-`) or both (`+-`) strands, `case_*` and
+/// `control_*` are the base counts in the two BAM files, and `ref`
+/// and `alt` alleles are called from the combined counts.  Without
+/// the reference genome, the major allele is reported as `ref`.
+/// Each (chr, pos, strand) is tested for case vs. control with the
+/// counts pooled over samples, and its `log_odds`, `pvalue` and
+/// Benjamini-Hochberg `qvalue` are repeated on all of its rows.
+///
+/// @param fg_bam case (foreground) BAM or CRAM file
+/// @param bg_bam control (background) BAM or CRAM file
+/// @param block_size genomic block size for parallel sweeps
+/// @param conversion only keep positions showing these conversions
+/// on the sense strand, e.g., `"C>T"` or `"C>T,A>G"`
+/// @param reference indexed reference FASTA file (plain or bgzipped),
+/// also required to decode CRAM files
+/// @param test statistical test for case vs. control: `"fisher"`
+/// (default), `"beta-binomial"` or `"overdispersed"`
+/// @param overdispersion overdispersion for the beta-binomial test
+/// (default: 0.01)
+/// @param min_mapq minimum mapping quality of reads (default: 20)
+/// @param min_base_quality minimum Phred base quality (default: 20)
+/// @param trim_ends ignore this many bases at both ends of the
+/// aligned part of reads (default: 0)
+/// @param umi_tag count reads sharing the same UMI in this tag once
+/// per cell and position, keeping duplicate-flagged reads with a UMI
+/// for the vote (default: `"UB"`; `""` to count every read)
+/// @param cell_tag tag for cell barcodes or samples, e.g., `"CB"`,
+/// `"CR"`, `"RG"`, `"BC"` (default: `"CB"`; `""` to combine all reads)
+/// @param whitelist file of cell barcodes to keep (first column)
+/// @param unlisted_to_combined count reads of barcodes not in the
+/// whitelist toward the sample `.` of reads without a cell barcode
+/// instead of dropping them
+/// @param library_type how reads map to their transcript strands:
+/// `"unstranded"` (default; read orientation), `"fr-firststrand"`,
+/// `"fr-secondstrand"`, `"10x-3p"` or `"10x-5p"`
+/// @param bam_threads extra htslib threads to decompress each BAM
+/// reader
+/// @param mtx_output if given, also write reference and alternate
+/// allele count matrices (sites x barcodes) in 10x directories
+/// `{mtx_output}/ref` and `{mtx_output}/alt` (readable by
+/// `Seurat::Read10X`), with the sites in `{mtx_output}/sites.tsv.gz`
+/// @param return_matrices if `TRUE`, return a list of the `sites`
+/// data frame, the `ref` and `alt` allele count matrices (sites x
+/// barcodes) in sparse column-compressed components, and a
+/// `features` data frame (id, chr, pos, strand, ref, alt) of their
+/// rows.  Each matrix can be wrapped without densifying by
+/// `Matrix::sparseMatrix(i = m$i, p = m$p, x = m$x, dims = m$dims,
+/// dimnames = m$dimnames, index1 = FALSE)`.
+/// @param bed_file only visit the regions in this BED file
+/// @param contigs only visit these contigs, e.g., `"chr1,chr2"`
+/// @param min_contig_len skip contigs shorter than this, e.g.,
+/// scaffolds and decoys
+/// @param gff_file only visit the features in this GFF/GTF file
+/// @param feature_types GFF feature types to take the regions from
+/// (default: `"exon"`)
+/// @param index_type BAM index to use or build: `"auto"` (default;
+/// any existing index, or else BAI, or CSI for targets longer than
+/// 2^29 - 1 bp), `"bai"` or `"csi"`
+/// @param check_index also check that the BAM index points to the
+/// reads it counts on a sample of targets (default: `FALSE`)
+/// @param stale_index what to do with indexes older than the BAM
+/// file or not matching it: `"rebuild"` (default) or `"error"`
+/// @param index_dir read-only mode: never write next to the BAM
+/// files, but look for and build indexes in this directory
 ///
 /// @export
 #[extendr]
-fn compare_case_control_bam(fg_bam: &str, bg_bam: &str, block_size: Option<usize>) -> List {
-    // sift::compare::search_case_control()
+fn compare_case_control_bam(
+    fg_bam: &str,
+    bg_bam: &str,
+    #[extendr(default = "NULL")] block_size: Option<usize>,
+    #[extendr(default = "NULL")] conversion: Option<&str>,
+    #[extendr(default = "NULL")] reference: Option<&str>,
+    #[extendr(default = "NULL")] test: Option<&str>,
+    #[extendr(default = "NULL")] overdispersion: Option<f64>,
+    #[extendr(default = "NULL")] min_mapq: Option<usize>,
+    #[extendr(default = "NULL")] min_base_quality: Option<usize>,
+    #[extendr(default = "NULL")] trim_ends: Option<usize>,
+    #[extendr(default = "NULL")] umi_tag: Option<&str>,
+    #[extendr(default = "NULL")] cell_tag: Option<&str>,
+    #[extendr(default = "NULL")] whitelist: Option<&str>,
+    #[extendr(default = "NULL")] unlisted_to_combined: Option<bool>,
+    #[extendr(default = "NULL")] library_type: Option<&str>,
+    #[extendr(default = "NULL")] bam_threads: Option<usize>,
+    #[extendr(default = "NULL")] mtx_output: Option<&str>,
+    #[extendr(default = "NULL")] return_matrices: Option<bool>,
+    #[extendr(default = "NULL")] bed_file: Option<&str>,
+    #[extendr(default = "NULL")] contigs: Option<&str>,
+    #[extendr(default = "NULL")] min_contig_len: Option<usize>,
+    #[extendr(default = "NULL")] gff_file: Option<&str>,
+    #[extendr(default = "NULL")] feature_types: Option<&str>,
+    #[extendr(default = "NULL")] index_type: Option<&str>,
+    #[extendr(default = "NULL")] check_index: Option<bool>,
+    #[extendr(default = "NULL")] stale_index: Option<&str>,
+    #[extendr(default = "NULL")] index_dir: Option<&str>,
+) -> Result<Robj> {
+    let mut config = SiftConfig {
+        block_size,
+        bam_threads,
+        reference: reference.map(|x| x.into()),
+        ..Default::default()
+    };
+
+    if let Some(x) = min_mapq {
+        config.read_filters.min_mapq = x.min(255) as u8;
+    }
+    if let Some(x) = min_base_quality {
+        config.read_filters.min_base_quality = x.min(255) as u8;
+    }
+    if let Some(x) = trim_ends {
+        config.read_filters.trim_ends = x;
+    }
+    if let Some(x) = umi_tag {
+        config.read_filters.umi_tag = x.into();
+    }
+    if let Some(x) = cell_tag {
+        config.read_filters.cell_tag = x.into();
+    }
+    if let Some(x) = whitelist {
+        let whitelist =
+            Whitelist::from_file(x).map_err(|e| r_error("failed to read the whitelist", e))?;
+        config.read_filters.whitelist = Some(whitelist);
+    }
+    if let Some(x) = unlisted_to_combined {
+        config.read_filters.unlisted_to_combined = x;
+    }
+    if let Some(x) = library_type {
+        let library_type =
+            LibraryType::from_str(x, true).map_err(|e| r_error("invalid library type", e))?;
+        config.read_filters.library_type = library_type;
+    }
+
+    config.regions = RegionFilters {
+        bed: bed_file.map(|x| x.into()),
+        gff: gff_file.map(|x| x.into()),
+        ..Default::default()
+    };
+    if let Some(x) = contigs {
+        config.regions.contigs = x.split(',').map(|x| x.into()).collect();
+    }
+    if let Some(x) = min_contig_len {
+        config.regions.min_contig_len = x as i64;
+    }
+    if let Some(x) = feature_types {
+        config.regions.feature_types = x.split(',').map(|x| x.into()).collect();
+    }
+
+    config.index_options = index_options(index_type, check_index, stale_index, index_dir)?;
+
+    let test = match test {
+        Some(x) => SiteTest::from_str(x, true).map_err(|e| r_error("invalid test", e))?,
+        None => SiteTest::Fisher,
+    };
+
+    if let Some(conversion) = conversion {
+        let specs: Vec<&str> = conversion.split(',').collect();
+        let rules =
+            ConversionRules::parse(&specs).map_err(|e| r_error("invalid conversion rules", e))?;
+        config.base_filters = config.base_filters.with_conversions(rules);
+    }
+
+    let sites = sift::compare::search_case_control(fg_bam, bg_bam, &config)
+        .map_err(|e| r_error("failed to sift through the BAM files", e))?;
 
-    println!("Establishing BAM File Sifters...");
+    if let Some(output) = mtx_output {
+        SiteMatrices::from_sites(&sites)
+            .write(output)
+            .map_err(|e| r_error("failed to write the matrices", e))?;
+    }
 
-    let fg_bai = (fg_bam.to_string() + ".bai").into_boxed_str();
-    let bg_bai = (bg_bam.to_string() + ".bai").into_boxed_str();
+    let results = test_case_control_sites(&sites, test, overdispersion.unwrap_or(0.01));
+    let sites_df = sites_to_data_frame(&sites, &results)?;
 
-    let _ = check_bam_index(fg_bam, Some(&fg_bai))
-        .expect(&format!("failed to generate index for: {}", fg_bam));
+    if !return_matrices.unwrap_or(false) {
+        return Ok(sites_df);
+    }
 
-    let _ = check_bam_index(bg_bam, Some(&bg_bai))
-        .expect(&format!("failed to generate index for: {}", bg_bam));
+    let matrices = SiteMatrices::from_sites(&sites);
+    let features = matrices.feature_ids();
+
+    let mut chr = Vec::with_capacity(matrices.nrow());
+    let mut pos = Vec::with_capacity(matrices.nrow());
+    let mut strand = Vec::with_capacity(matrices.nrow());
+    let mut ref_base = Vec::with_capacity(matrices.nrow());
+    let mut alt_base = Vec::with_capacity(matrices.nrow());
+    for (c, p, s, a1, a2) in matrices.sites.iter() {
+        chr.push(c.to_string());
+        pos.push((p + 1) as f64);
+        strand.push(s.to_string());
+        ref_base.push(a1.to_string());
+        alt_base.push(a2.to_string());
+    }
+    let ids: Vec<String> = features.iter().map(|x| x.to_string()).collect();
+    let columns: Vec<Robj> = vec![
+        ids.into(),
+        chr.into(),
+        pos.into(),
+        strand.into(),
+        ref_base.into(),
+        alt_base.into(),
+    ];
+    let columns =
+        List::from_names_and_values(["id", "chr", "pos", "strand", "ref", "alt"], columns)?;
+    let features_df = call!("as.data.frame", columns, stringsAsFactors = false)?;
+
+    let values: Vec<Robj> = vec![
+        sites_df,
+        sparse_to_robj(&matrices.ref_counts, &matrices, &features),
+        sparse_to_robj(&matrices.alt_counts, &matrices, &features),
+        features_df,
+    ];
+    Ok(List::from_names_and_values(["sites", "ref", "alt", "features"], values)?.into())
+}
+
+/// Options to find or build BAM indexes
+fn index_options(
+    index_type: Option<&str>,
+    check_index: Option<bool>,
+    stale_index: Option<&str>,
+    index_dir: Option<&str>,
+) -> Result<IndexOptions> {
+    let mut ret = IndexOptions {
+        check_index: check_index.unwrap_or(false),
+        index_dir: index_dir.map(|x| x.into()),
+        ..Default::default()
+    };
+    if let Some(x) = index_type {
+        ret.index_type =
+            IndexType::from_str(x, true).map_err(|e| r_error("invalid index type", e))?;
+    }
+    if let Some(x) = stale_index {
+        ret.stale_index =
+            StaleIndex::from_str(x, true).map_err(|e| r_error("invalid stale index option", e))?;
+    }
+    Ok(ret)
+}
+
+/// Column-compressed components (`i`, `p`, `x`, `dims`, `dimnames`)
+/// of a sparse sites x barcodes matrix with 0-based indices
+fn sparse_to_robj(
+    triplets: &[(usize, usize, f32)],
+    matrices: &SiteMatrices,
+    features: &[Box<str>],
+) -> Robj {
+    let (nrow, ncol) = (matrices.nrow(), matrices.ncol());
+    let (i, p, x) = triplets_to_csc(triplets, ncol);
+
+    let i: Vec<i32> = i.into_iter().map(|v| v as i32).collect();
+    let p: Vec<i32> = p.into_iter().map(|v| v as i32).collect();
+    let x: Vec<f64> = x.into_iter().map(|v| v as f64).collect();
+    let rownames: Vec<String> = features.iter().map(|v| v.to_string()).collect();
+    let colnames: Vec<String> = matrices.barcodes.iter().map(|v| v.to_string()).collect();
+
+    list!(
+        i = i,
+        p = p,
+        x = x,
+        dims = vec![nrow as i32, ncol as i32],
+        dimnames = list!(rownames, colnames)
+    )
+    .into()
+}
 
-    let mut data_fg = sift::sifter::BamSifter::from_file(fg_bam, &fg_bai, block_size);
-    let mut data_bg = sift::sifter::BamSifter::from_file(bg_bam, &bg_bai, block_size);
+/// A data frame of the sites with 1-based positions and the test
+/// results of their (chr, pos, strand) groups
+fn sites_to_data_frame(sites: &[CaseControlSite], results: &[SiteTestResult]) -> Result<Robj> {
+    let nn = sites.len();
+    let mut chr = Vec::with_capacity(nn);
+    let mut pos = Vec::with_capacity(nn);
+    let mut strand = Vec::with_capacity(nn);
+    let mut sample = Vec::with_capacity(nn);
+    let mut found_on = Vec::with_capacity(nn);
+    let mut case_counts: [Vec<f64>; 4] = Default::default();
+    let mut control_counts: [Vec<f64>; 4] = Default::default();
+    let mut ref_base = Vec::with_capacity(nn);
+    let mut alt_base = Vec::with_capacity(nn);
+    let mut log_odds = Vec::with_capacity(nn);
+    let mut pvalue = Vec::with_capacity(nn);
+    let mut qvalue = Vec::with_capacity(nn);
 
-    println!("Searching for variable positions");
+    for (group, res) in group_sites(sites).into_iter().zip(results) {
+        for site in group.iter() {
+            chr.push(site.chr.to_string());
+            pos.push((site.pos + 1) as f64);
+            strand.push(site.strand.to_string());
+            sample.push(site.sample.to_string());
+            found_on.push(site.found_on.to_string());
 
-    data_fg.sweep_variable_positions();
-    data_bg.sweep_variable_positions();
+            for (j, b) in [Dna::A, Dna::T, Dna::G, Dna::C].into_iter().enumerate() {
+                case_counts[j].push(site.case.get(b) as f64);
+                control_counts[j].push(site.control.get(b) as f64);
+            }
 
-    // update variable positions to each other
-    data_bg.add_missed_positions(&data_fg);
-    data_fg.add_missed_positions(&data_bg);
+            let stat = site.ref_alt_stat();
+            ref_base.push(stat.a1.to_string());
+            alt_base.push(stat.a2.to_string());
 
-    println!("Collecting sufficient statistics");
+            log_odds.push(res.log_odds);
+            pvalue.push(res.pvalue);
+            qvalue.push(res.qvalue);
+        }
+    }
 
-    data_fg.populate_statistics();
-    data_bg.populate_statistics();
+    let mut columns: Vec<Robj> = vec![
+        chr.into(),
+        pos.into(),
+        strand.into(),
+        sample.into(),
+        found_on.into(),
+    ];
+    columns.extend(case_counts.into_iter().map(Robj::from));
+    columns.extend(control_counts.into_iter().map(Robj::from));
+    columns.push(ref_base.into());
+    columns.push(alt_base.into());
+    columns.push(log_odds.into());
+    columns.push(pvalue.into());
+    columns.push(qvalue.into());
 
-    todo!("need to report");
+    let header = CaseControlSite::tsv_header();
+    let mut names: Vec<&str> = header.split('\t').collect();
+    names.extend(["log_odds", "pvalue", "qvalue"]);
+    let columns = List::from_names_and_values(names, columns)?;
+
+    call!("as.data.frame", columns, stringsAsFactors = false)
+}
+
+/// Infer the library type (strandedness) of a BAM file by sampling
+/// reads over GFF exons and comparing their orientations with the
+/// gene strands, in the spirit of RSeQC `infer_experiment.py`.
+///
+/// Returns a list of the most likely `library_type`, the fraction of
+/// reads in `agreement` with it, the number of informative reads
+/// `nreads`, the fraction of reads with cell barcodes, the agreement
+/// of each candidate library type, and a data frame of read counts by
+/// mate (`.` for single-end reads), read strand and gene strand.
+///
+/// @param bam_file BAM or CRAM file
+/// @param gff_file GFF/GTF file
+/// @param feature_types GFF feature types to take the gene strands
+/// from, separated by commas (default: `"exon"`)
+/// @param max_reads number of reads to sample (default: 200000)
+/// @param min_agreement minimum fraction of reads agreeing with gene
+/// strands to call a stranded library (default: 0.75)
+/// @param min_mapq minimum mapping quality of reads (default: 20)
+/// @param cell_tag tag for cell barcodes (default: `"CB"`)
+/// @param index_type BAM index to use or build: `"auto"` (default),
+/// `"bai"` or `"csi"`
+/// @param check_index also check that the BAM index points to the
+/// reads it counts on a sample of targets (default: `FALSE`)
+/// @param stale_index what to do with indexes older than the BAM
+/// file or not matching it: `"rebuild"` (default) or `"error"`
+/// @param index_dir read-only mode: never write next to the BAM
+/// files, but look for and build indexes in this directory
+/// @param reference reference FASTA file to decode CRAM files
+///
+/// @export
+#[extendr]
+fn infer_library_type(
+    bam_file: &str,
+    gff_file: &str,
+    #[extendr(default = "NULL")] feature_types: Option<&str>,
+    #[extendr(default = "NULL")] max_reads: Option<usize>,
+    #[extendr(default = "NULL")] min_agreement: Option<f64>,
+    #[extendr(default = "NULL")] min_mapq: Option<usize>,
+    #[extendr(default = "NULL")] cell_tag: Option<&str>,
+    #[extendr(default = "NULL")] index_type: Option<&str>,
+    #[extendr(default = "NULL")] check_index: Option<bool>,
+    #[extendr(default = "NULL")] stale_index: Option<&str>,
+    #[extendr(default = "NULL")] index_dir: Option<&str>,
+    #[extendr(default = "NULL")] reference: Option<&str>,
+) -> Result<Robj> {
+    let mut read_filters = ReadFilters::default();
+    if let Some(x) = min_mapq {
+        read_filters.min_mapq = x.min(255) as u8;
+    }
+    if let Some(x) = cell_tag {
+        read_filters.cell_tag = x.into();
+    }
+
+    let feature_types: Vec<Box<str>> = feature_types
+        .unwrap_or("exon")
+        .split(',')
+        .map(|x| x.into())
+        .collect();
+
+    let index_options = index_options(index_type, check_index, stale_index, index_dir)?;
+
+    check_cram_reference(bam_file, reference)
+        .map_err(|e| r_error("cannot read the BAM file", e))?;
+    let index_file = check_bam_index(bam_file, None, &index_options)
+        .map_err(|e| r_error("failed to find the BAM index", e))?;
+
+    let mut bam_readers = BamReaderPool::new(bam_file, &index_file)
+        .map_err(|e| r_error("failed to open the BAM file", e))?;
+    if let Some(fasta_file) = reference {
+        bam_readers
+            .set_reference(fasta_file)
+            .map_err(|e| r_error("failed to set the reference", e))?;
+    }
+
+    let report = strandedness::infer_library_type(
+        &bam_readers,
+        gff_file,
+        &feature_types,
+        &read_filters,
+        max_reads.unwrap_or(strandedness::DEFAULT_MAX_READS),
+        min_agreement.map_or(strandedness::DEFAULT_MIN_AGREEMENT, |x| x as f32),
+    )
+    .map_err(|e| r_error("failed to infer the library type", e))?;
+
+    let mut mate = vec![];
+    let mut read_strand = vec![];
+    let mut gene_strand = vec![];
+    let mut count = vec![];
+    for (m, r, g, n) in report.count_table() {
+        mate.push(m.to_string());
+        read_strand.push(r.to_string());
+        gene_strand.push(g.to_string());
+        count.push(n as f64);
+    }
+    let counts = List::from_names_and_values(
+        ["mate", "read_strand", "gene_strand", "count"],
+        [
+            Robj::from(mate),
+            read_strand.into(),
+            gene_strand.into(),
+            count.into(),
+        ],
+    )?;
+    let counts = call!("as.data.frame", counts, stringsAsFactors = false)?;
+
+    let candidates = LibraryType::value_variants();
+    let agreements = List::from_names_and_values(
+        candidates.iter().map(|x| x.to_string()),
+        candidates
+            .iter()
+            .map(|&x| report.agreement_of(x) as f64)
+            .map(Robj::from),
+    )?;
+
+    Ok(list!(
+        library_type = report.library_type.to_string(),
+        agreement = report.agreement as f64,
+        nreads = report.nreads as f64,
+        barcoded = report.barcoded as f64,
+        agreements = agreements,
+        counts = counts
+    )
+    .into())
+}
 
-    list!()
+/// Turn a failure into an R error condition that `tryCatch` can
+/// handle, instead of a panic
+fn r_error(context: &str, e: impl std::fmt::Display) -> Error {
+    Error::Other(format!("{}: {:#}", context, e))
 }
 
 // Macro to generate exports.
@@ -59,4 +494,5 @@ fn compare_case_control_bam(fg_bam: &str, bg_bam: &str, block_size: Option<usize
 extendr_module! {
     mod faba;
     fn compare_case_control_bam;
+    fn infer_library_type;
 }
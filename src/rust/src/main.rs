@@ -1,4 +1,41 @@
-fn main()
-{
+mod aggregate;
+mod depth;
+mod sift;
+mod util;
 
+use clap::{Parser, Subcommand};
+
+/// Filtering Alignment data for Base-level Analysis
+///
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+#[command(propagate_version = true)]
+struct Cli {
+    #[command(subcommand)]
+    commands: Commands,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Sift through case and control BAM files to identify
+    /// candidate variable sites
+    Sift(sift::SiftArgs),
+    /// Count strand-specific read depth over GFF features
+    Depth(depth::DepthArgs),
+    /// Collect site statistics over GFF features
+    Aggregate(aggregate::AggArgs),
+    /// Infer the library type (strandedness) from reads over GFF
+    /// exons
+    InferLibrary(depth::InferArgs),
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+
+    match &cli.commands {
+        Commands::Sift(args) => sift::run::run_sift(args),
+        Commands::Depth(args) => depth::run::run_depth(args),
+        Commands::Aggregate(args) => aggregate::run_agg(args),
+        Commands::InferLibrary(args) => depth::infer::run_infer(args),
+    }
 }
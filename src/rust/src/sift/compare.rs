@@ -0,0 +1,73 @@
+use crate::sift::report::{collect_case_control_sites, CaseControlSite};
+use crate::sift::rules::BaseFilters;
+use crate::sift::sifter::BamSifter;
+use crate::util::bam::{check_bam_index, check_cram_reference, IndexOptions, ReadFilters};
+use crate::util::regions::RegionFilters;
+
+/// Options shared by case and control sifters
+///
+#[derive(Clone, Default)]
+pub struct SiftConfig {
+    /// genomic block size for parallel sweeps
+    pub block_size: Option<usize>,
+    /// extra htslib threads to decompress each BAM reader
+    pub bam_threads: Option<usize>,
+    /// filters on reads and bases
+    pub read_filters: ReadFilters,
+    /// filters to decide variable positions
+    pub base_filters: BaseFilters,
+    /// indexed reference FASTA file (required for CRAM files)
+    pub reference: Option<Box<str>>,
+    /// regions to visit
+    pub regions: RegionFilters,
+    /// how to find or build BAM indexes
+    pub index_options: IndexOptions,
+}
+
+/// Search for candidate sites that are variable in the case and
+/// control BAM files combined, and collect their statistics in both
+/// within the same pass.
+///
+/// * `fg_bam` - case (foreground) BAM or CRAM file
+/// * `bg_bam` - control (background) BAM or CRAM file
+/// * `config` - sifting options
+///
+pub fn search_case_control(
+    fg_bam: &str,
+    bg_bam: &str,
+    config: &SiftConfig,
+) -> anyhow::Result<Vec<CaseControlSite>> {
+    println!("Establishing BAM File Sifters...");
+
+    check_cram_reference(fg_bam, config.reference.as_deref())?;
+    check_cram_reference(bg_bam, config.reference.as_deref())?;
+
+    let fg_idx = check_bam_index(fg_bam, None, &config.index_options)?;
+    let bg_idx = check_bam_index(bg_bam, None, &config.index_options)?;
+
+    let mut sifter = BamSifter::from_files(
+        &[(fg_bam, &fg_idx), (bg_bam, &bg_idx)],
+        config.block_size,
+        &config.read_filters,
+    )?;
+
+    sifter.set_regions(&config.regions)?;
+    sifter.set_base_filters(config.base_filters.clone());
+    if let Some(threads) = config.bam_threads {
+        sifter.set_bam_threads(threads)?;
+    }
+    if let Some(fasta) = config.reference.as_deref() {
+        sifter.set_reference(fasta)?;
+    }
+
+    println!(
+        "Sifting through {} blocks of case and control BAM files together",
+        sifter.num_blocks()
+    );
+
+    let sites = sifter.sift()?;
+
+    println!("Reporting candidate sites");
+
+    Ok(collect_case_control_sites(sites))
+}
@@ -0,0 +1,125 @@
+use crate::sift::report::{group_sites, pooled_ref_alt, CaseControlSite};
+use crate::sift::*;
+use crate::util::file::write_lines;
+use crate::util::mtx::write_mtx;
+
+use std::collections::{BTreeSet, HashMap};
+
+/// feature type in the third column of `features.tsv.gz`
+const FEATURE_TYPE: &str = "Allele Count";
+
+/// Reference and alternate allele counts of sites (rows) by cell
+/// barcodes (columns), as in 10x feature-barcode matrices.  Columns
+/// are named `case:<barcode>` and `control:<barcode>`, where the
+/// reads without barcodes are `case:.` and `control:.`.
+///
+pub struct SiteMatrices {
+    /// (chr, pos, strand, ref, alt) of each row
+    pub sites: Vec<(Box<str>, i64, DnaStrand, Dna, Dna)>,
+    pub barcodes: Vec<Box<str>>,
+    /// 0-based (row, column, count) triplets sorted by row and column
+    pub ref_counts: Vec<(usize, usize, f32)>,
+    /// 0-based (row, column, count) triplets sorted by row and column
+    pub alt_counts: Vec<(usize, usize, f32)>,
+}
+
+#[allow(dead_code)]
+impl SiteMatrices {
+    /// * `sites` - sorted by (chr, pos, strand, sample)
+    pub fn from_sites(sites: &[CaseControlSite]) -> Self {
+        let samples: BTreeSet<&BamSample> = sites.iter().map(|x| &x.sample).collect();
+        let nsamples = samples.len();
+
+        // sample -> column of the case (and + nsamples for control)
+        let columns: HashMap<&BamSample, usize> =
+            samples.iter().enumerate().map(|(j, &s)| (s, j)).collect();
+
+        let mut barcodes = Vec::with_capacity(2 * nsamples);
+        for condition in ["case", "control"] {
+            barcodes.extend(
+                samples
+                    .iter()
+                    .map(|s| format!("{}:{}", condition, s).into_boxed_str()),
+            );
+        }
+
+        let mut ret = SiteMatrices {
+            sites: vec![],
+            barcodes,
+            ref_counts: vec![],
+            alt_counts: vec![],
+        };
+
+        for (i, group) in group_sites(sites).into_iter().enumerate() {
+            let first = &group[0];
+            let BiAllele { a1, a2, .. } = pooled_ref_alt(group);
+            ret.sites
+                .push((first.chr.clone(), first.pos, first.strand, a1, a2));
+
+            for (k, offset) in [(0, 0), (1, nsamples)] {
+                for s in group {
+                    let stat = match k {
+                        0 => &s.case,
+                        _ => &s.control,
+                    };
+                    let j = columns[&s.sample] + offset;
+                    if stat.get(a1) > 0_f32 {
+                        ret.ref_counts.push((i, j, stat.get(a1)));
+                    }
+                    if stat.get(a2) > 0_f32 {
+                        ret.alt_counts.push((i, j, stat.get(a2)));
+                    }
+                }
+            }
+        }
+        ret
+    }
+
+    pub fn nrow(&self) -> usize {
+        self.sites.len()
+    }
+
+    pub fn ncol(&self) -> usize {
+        self.barcodes.len()
+    }
+
+    /// site IDs like `chr1:1234:+` with a 1-based position
+    pub fn feature_ids(&self) -> Vec<Box<str>> {
+        self.sites
+            .iter()
+            .map(|(chr, pos, strand, _, _)| format!("{}:{}:{}", chr, pos + 1, strand).into())
+            .collect()
+    }
+
+    /// Write the matrices in 10x directories `{output}/ref/` and
+    /// `{output}/alt/`, each with `matrix.mtx.gz`, `barcodes.tsv.gz`
+    /// and `features.tsv.gz` (id, name, type), and the sites of the
+    /// rows to `{output}/sites.tsv.gz` (id, chr, pos, strand, ref,
+    /// alt)
+    pub fn write(&self, output: &str) -> anyhow::Result<()> {
+        let (nrow, ncol) = (self.nrow(), self.ncol());
+        let ids = self.feature_ids();
+
+        let feature_lines: Vec<Box<str>> = ids
+            .iter()
+            .map(|id| format!("{}\t{}\t{}", id, id, FEATURE_TYPE).into_boxed_str())
+            .collect();
+
+        for (allele, triplets) in [("ref", &self.ref_counts), ("alt", &self.alt_counts)] {
+            let dir = format!("{}/{}", output, allele);
+            std::fs::create_dir_all(&dir)?;
+            write_mtx(triplets, nrow, ncol, &format!("{}/matrix.mtx.gz", dir))?;
+            write_lines(&self.barcodes, &format!("{}/barcodes.tsv.gz", dir))?;
+            write_lines(&feature_lines, &format!("{}/features.tsv.gz", dir))?;
+        }
+
+        let mut site_lines: Vec<Box<str>> = vec!["id\tchr\tpos\tstrand\tref\talt".into()];
+        site_lines.extend(ids.iter().zip(self.sites.iter()).map(
+            |(id, (chr, pos, strand, a1, a2))| {
+                format!("{}\t{}\t{}\t{}\t{}\t{}", id, chr, pos + 1, strand, a1, a2).into_boxed_str()
+            },
+        ));
+        write_lines(&site_lines, &format!("{}/sites.tsv.gz", output))?;
+        Ok(())
+    }
+}
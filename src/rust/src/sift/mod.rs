@@ -1,5 +1,59 @@
+pub mod compare;
+pub mod matrix;
+pub mod report;
 pub mod rules;
+pub mod run;
 pub mod sifter;
+pub mod test;
+pub mod vcf;
 
 use crate::util::bam::*;
 use crate::util::dna::*;
+use crate::util::regions::RegionFilters;
+
+use clap::Args;
+
+#[derive(Args)]
+pub struct SiftArgs {
+    /// Case (foreground) BAM or CRAM file
+    fg_bam: Box<str>,
+    /// Control (background) BAM or CRAM file
+    bg_bam: Box<str>,
+    /// Genomic block size for parallel sweeps
+    #[arg(short, long)]
+    block_size: Option<usize>,
+    /// Extra htslib threads to decompress each BAM reader
+    #[arg(long)]
+    bam_threads: Option<usize>,
+    #[command(flatten)]
+    read_filters: ReadFilters,
+    #[command(flatten)]
+    regions: RegionFilters,
+    #[command(flatten)]
+    index_options: IndexOptions,
+    /// Only keep positions showing these conversions on the sense
+    /// strand, e.g., `C>T` for C-to-U or `A>G` for A-to-I editing
+    #[arg(short, long, value_delimiter = ',')]
+    conversion: Vec<Box<str>>,
+    /// Indexed reference FASTA file (plain or bgzipped), also to
+    /// decode CRAM files
+    #[arg(short, long)]
+    reference: Option<Box<str>>,
+    /// Statistical test for case vs. control
+    #[arg(short, long, value_enum, default_value = "fisher")]
+    test: test::SiteTest,
+    /// Overdispersion for the beta-binomial test
+    #[arg(long, default_value_t = 0.01)]
+    overdispersion: f64,
+    /// Also write the sites with test statistics in VCF or BCF
+    #[arg(long, value_enum)]
+    vcf: Option<vcf::VcfFormat>,
+    /// Also write reference and alternate allele count matrices
+    /// (sites x barcodes) in 10x directories `{output}/ref` and
+    /// `{output}/alt`
+    #[arg(long, default_value_t = false)]
+    mtx: bool,
+    /// Output file header
+    #[arg(short, long)]
+    output: Box<str>,
+}
@@ -0,0 +1,149 @@
+use crate::sift::*;
+
+/// On which strand(s) a site was found variable during the sweep
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FoundOn {
+    Forward,
+    Reverse,
+    Both,
+}
+
+impl std::fmt::Display for FoundOn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FoundOn::Forward => write!(f, "+"),
+            FoundOn::Reverse => write!(f, "-"),
+            FoundOn::Both => write!(f, "+-"),
+        }
+    }
+}
+
+/// A site observed in a sample (or a cell barcode) with the
+/// statistics in each of the jointly sifted BAM files
+///
+pub struct SiftedSite {
+    pub chr: Box<str>,
+    pub pos: i64,
+    pub strand: DnaStrand,
+    pub sample: BamSample,
+    pub found_on: FoundOn,
+    pub stats: Vec<DnaBaseStat>,
+}
+
+/// A candidate site observed in a sample (or a cell barcode),
+/// pairing up the case and control statistics.
+///
+pub struct CaseControlSite {
+    pub chr: Box<str>,
+    pub pos: i64,
+    pub strand: DnaStrand,
+    pub sample: BamSample,
+    pub found_on: FoundOn,
+    pub case: DnaBaseStat,
+    pub control: DnaBaseStat,
+}
+
+#[allow(dead_code)]
+impl CaseControlSite {
+    /// reference and alternate alleles with the case and control
+    /// counts combined
+    pub fn ref_alt_stat(&self) -> BiAllele {
+        let mut stat = self.case.clone();
+        stat.add_stat(&self.control);
+        stat.ref_alt_stat()
+    }
+
+    pub fn tsv_header() -> Box<str> {
+        let mut words: Vec<String> = ["chr", "pos", "strand", "sample", "found_on"]
+            .iter()
+            .map(|x| x.to_string())
+            .collect();
+        for side in ["case", "control"] {
+            for b in [Dna::A, Dna::T, Dna::G, Dna::C] {
+                words.push(format!("{}_{}", side, b));
+            }
+        }
+        words.push("ref".to_string());
+        words.push("alt".to_string());
+        words.join("\t").into_boxed_str()
+    }
+
+    /// a tab-separated line with 1-based position
+    pub fn to_tsv(&self) -> Box<str> {
+        let mut words = vec![
+            self.chr.to_string(),
+            (self.pos + 1).to_string(),
+            self.strand.to_string(),
+            self.sample.to_string(),
+            self.found_on.to_string(),
+        ];
+        for stat in [&self.case, &self.control] {
+            for b in [Dna::A, Dna::T, Dna::G, Dna::C] {
+                words.push(stat.get(b).to_string());
+            }
+        }
+        let stat = self.ref_alt_stat();
+        words.push(stat.a1.to_string());
+        words.push(stat.a2.to_string());
+        words.join("\t").into_boxed_str()
+    }
+}
+
+/// Pair up the case and control statistics of the jointly sifted
+/// sites, keeping them sorted by (chr, pos, strand, sample).
+///
+/// * `sites` - sites sifted from the case and control BAM files (in
+///   this order)
+///
+pub fn collect_case_control_sites(sites: Vec<SiftedSite>) -> Vec<CaseControlSite> {
+    sites
+        .into_iter()
+        .map(|site| {
+            let mut stats = site.stats.into_iter();
+            let case = stats.next().unwrap_or_else(|| DnaBaseStat::new(site.pos));
+            let control = stats.next().unwrap_or_else(|| DnaBaseStat::new(site.pos));
+            CaseControlSite {
+                chr: site.chr,
+                pos: site.pos,
+                strand: site.strand,
+                sample: site.sample,
+                found_on: site.found_on,
+                case,
+                control,
+            }
+        })
+        .collect()
+}
+
+/// Group consecutive sites of the same (chr, pos, strand), e.g.,
+/// different samples of a site
+///
+/// * `sites` - sorted by (chr, pos, strand, sample)
+///
+pub fn group_sites(sites: &[CaseControlSite]) -> Vec<&[CaseControlSite]> {
+    let mut groups = vec![];
+    let mut lb = 0;
+    for ub in 1..=sites.len() {
+        if ub == sites.len()
+            || (&sites[ub].chr, sites[ub].pos, sites[ub].strand)
+                != (&sites[lb].chr, sites[lb].pos, sites[lb].strand)
+        {
+            groups.push(&sites[lb..ub]);
+            lb = ub;
+        }
+    }
+    groups
+}
+
+/// Reference and alternate alleles of a group of sites, with the
+/// counts combined over all the samples and both conditions
+///
+pub fn pooled_ref_alt(group: &[CaseControlSite]) -> BiAllele {
+    let mut pooled = DnaBaseStat::new(group.first().map_or(0, |x| x.pos));
+    for s in group {
+        pooled.add_stat(&s.case);
+        pooled.add_stat(&s.control);
+    }
+    pooled.ref_alt_stat()
+}
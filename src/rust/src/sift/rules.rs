@@ -2,10 +2,113 @@ use crate::util::dna::*;
 // use std::cmp::{max, min};
 // use fastapprox::faster as fa;;
 
+/// A base conversion from a reference base to one or more alternate
+/// bases, e.g., `C>T` for C-to-U editing (DART-seq/APOBEC) or `A>G`
+/// for A-to-I editing
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct Conversion {
+    pub from: Dna,
+    pub to: Vec<Dna>,
+}
+
+#[allow(dead_code)]
+impl Conversion {
+    /// Parse `FROM>TO`, where `TO` can list several alternates,
+    /// e.g., `C>T` or `A>GC`
+    pub fn parse(spec: &str) -> anyhow::Result<Self> {
+        let Some((from, to)) = spec.trim().split_once('>') else {
+            return Err(anyhow::anyhow!("expected FROM>TO, but got {}", spec));
+        };
+
+        let from = match from.trim().as_bytes() {
+            [b] => Dna::from_byte(*b),
+            _ => None,
+        }
+        .ok_or_else(|| anyhow::anyhow!("invalid reference base in {}", spec))?;
+
+        let to = to
+            .trim()
+            .bytes()
+            .map(|b| {
+                Dna::from_byte(b).ok_or_else(|| anyhow::anyhow!("invalid alternate in {}", spec))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        if to.is_empty() || to.contains(&from) {
+            return Err(anyhow::anyhow!("invalid alternate bases in {}", spec));
+        }
+
+        Ok(Conversion { from, to })
+    }
+
+    /// the same conversion seen on the other strand
+    pub fn complement(&self) -> Self {
+        Conversion {
+            from: self.from.complement(),
+            to: self.to.iter().map(|x| x.complement()).collect(),
+        }
+    }
+
+    /// Does `stat` show this conversion with respect to the given
+    /// reference base?
+    pub fn matches(&self, stat: &DnaBaseStat, reference: Dna) -> bool {
+        reference == self.from && self.to.iter().any(|&b| stat.get(b) > 0_f32)
+    }
+}
+
+/// Conversions expected from a given chemistry.  The rules are
+/// written for the sense (forward) strand, and the complementary
+/// conversions are applied to the reverse strand, e.g., `C>T` on the
+/// forward strand will show as `G>A` on the reverse strand.
+///
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConversionRules {
+    forward: Vec<Conversion>,
+    reverse: Vec<Conversion>,
+}
+
+#[allow(dead_code)]
+impl ConversionRules {
+    pub fn new(conversions: Vec<Conversion>) -> Self {
+        let reverse = conversions.iter().map(|x| x.complement()).collect();
+        ConversionRules {
+            forward: conversions,
+            reverse,
+        }
+    }
+
+    /// Parse a list of conversions, e.g., `["C>T", "A>G"]`
+    pub fn parse<S: AsRef<str>>(specs: &[S]) -> anyhow::Result<Self> {
+        let conversions = specs
+            .iter()
+            .map(|x| Conversion::parse(x.as_ref()))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self::new(conversions))
+    }
+
+    /// Does `stat` show any of the conversions on the given strand?
+    pub fn matches(&self, stat: &DnaBaseStat, reference: Dna, strand: DnaStrand) -> bool {
+        let conversions = match strand {
+            DnaStrand::Forward => &self.forward,
+            DnaStrand::Reverse => &self.reverse,
+        };
+        conversions.iter().any(|x| x.matches(stat, reference))
+    }
+}
+
+#[derive(Debug, Clone)]
 #[allow(dead_code)]
 pub struct BaseFilters {
     max_major_allele_cutoff: f32,
     min_minor_allele_cutoff: f32,
+    conversions: Option<ConversionRules>,
+}
+
+impl Default for BaseFilters {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[allow(dead_code)]
@@ -14,20 +117,85 @@ impl BaseFilters {
         BaseFilters {
             max_major_allele_cutoff: 1_f32 - 1e-4_f32,
             min_minor_allele_cutoff: 1e-4_f32,
+            conversions: None,
         }
     }
 
+    /// only keep positions showing these conversions
+    pub fn with_conversions(mut self, conversions: ConversionRules) -> Self {
+        self.conversions = Some(conversions);
+        self
+    }
+
     pub fn b_allele_frequency(&self, stat: &DnaBaseStat) -> f32 {
         let stat = stat.bi_allelic_stat();
         stat.n1 / (stat.n1 + stat.n2).max(1_f32)
     }
 
-    pub fn is_variable(&self, stat: &DnaBaseStat) -> bool {
-        let stat = stat.bi_allelic_stat();
-        stat.n1 > 0_f32 && stat.n2 > 0_f32
+    /// Is this position variable on the strand?  Without conversion
+    /// rules, any position with two or more alleles is variable.
+    /// Otherwise, the reference base (or the major allele if the
+    /// reference is unknown) is checked against the rules.
+    pub fn is_variable(&self, stat: &DnaBaseStat, strand: DnaStrand) -> bool {
+        match &self.conversions {
+            Some(rules) => {
+                let reference = stat.reference().unwrap_or(stat.most_frequent().0);
+                rules.matches(stat, reference, strand)
+            }
+            None => {
+                let stat = stat.bi_allelic_stat();
+                stat.n1 > 0_f32 && stat.n2 > 0_f32
+            }
+        }
     }
 
     pub fn is_near_zero_variance(&self, stat: &DnaBaseStat) -> bool {
         stat.most_frequent().1 > self.max_major_allele_cutoff
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stat_of(counts: &[(Dna, f32)]) -> DnaBaseStat {
+        let mut stat = DnaBaseStat::new(0);
+        for &(b, n) in counts {
+            stat.add(b, n);
+        }
+        stat
+    }
+
+    #[test]
+    fn conversion_parse() {
+        let conversion = Conversion::parse("C>T").unwrap();
+        assert_eq!(conversion.from, Dna::C);
+        assert_eq!(conversion.to, vec![Dna::T]);
+
+        let conversion = Conversion::parse(" a > gc ").unwrap();
+        assert_eq!(conversion.from, Dna::A);
+        assert_eq!(conversion.to, vec![Dna::G, Dna::C]);
+
+        for spec in ["C>C", "X>T", "C>TC", "CT", "C>", ">T", "CA>T", "C>N"] {
+            assert!(Conversion::parse(spec).is_err(), "{} parsed", spec);
+        }
+    }
+
+    #[test]
+    fn conversion_rules_match_both_strands() {
+        let rules = ConversionRules::parse(&["C>T"]).unwrap();
+
+        let c_to_t = stat_of(&[(Dna::C, 8_f32), (Dna::T, 2_f32)]);
+        assert!(rules.matches(&c_to_t, Dna::C, DnaStrand::Forward));
+        assert!(!rules.matches(&c_to_t, Dna::C, DnaStrand::Reverse));
+
+        // C>T on the reverse strand reads as G>A on the forward strand
+        let g_to_a = stat_of(&[(Dna::G, 8_f32), (Dna::A, 2_f32)]);
+        assert!(rules.matches(&g_to_a, Dna::G, DnaStrand::Reverse));
+        assert!(!rules.matches(&g_to_a, Dna::G, DnaStrand::Forward));
+
+        let g_only = stat_of(&[(Dna::G, 10_f32)]);
+        assert!(!rules.matches(&g_only, Dna::G, DnaStrand::Reverse));
+        assert!(!rules.matches(&g_to_a, Dna::C, DnaStrand::Reverse));
+    }
+}
@@ -0,0 +1,67 @@
+use crate::sift::compare::{search_case_control, SiftConfig};
+use crate::sift::matrix::SiteMatrices;
+use crate::sift::report::CaseControlSite;
+use crate::sift::rules::ConversionRules;
+use crate::sift::test::{test_case_control_sites, SiteTestResult};
+use crate::sift::vcf::write_vcf;
+use crate::util::bam::read_contigs;
+use crate::util::file::write_lines;
+
+pub fn run_sift(args: &super::SiftArgs) -> anyhow::Result<()> {
+    let mut config = SiftConfig {
+        block_size: args.block_size,
+        bam_threads: args.bam_threads,
+        read_filters: args.read_filters.clone(),
+        reference: args.reference.clone(),
+        regions: args.regions.clone(),
+        index_options: args.index_options.clone(),
+        ..Default::default()
+    };
+
+    if !args.conversion.is_empty() {
+        let rules = ConversionRules::parse(&args.conversion)?;
+        config.base_filters = config.base_filters.with_conversions(rules);
+    }
+
+    let sites = search_case_control(&args.fg_bam, &args.bg_bam, &config)?;
+
+    let mut lines = Vec::with_capacity(sites.len() + 1);
+    lines.push(CaseControlSite::tsv_header());
+    lines.extend(sites.iter().map(|x| x.to_tsv()));
+
+    let output = format!("{}.sites.tsv.gz", args.output);
+    write_lines(&lines, &output)?;
+    println!("Wrote {} sites to {}", sites.len(), output);
+
+    if args.mtx {
+        let matrices = SiteMatrices::from_sites(&sites);
+        matrices.write(&args.output)?;
+        println!(
+            "Wrote {} x {} allele count matrices to {}/ref and {}/alt",
+            matrices.nrow(),
+            matrices.ncol(),
+            args.output,
+            args.output
+        );
+    }
+
+    println!("Testing case vs. control: {:?}", args.test);
+
+    let results = test_case_control_sites(&sites, args.test, args.overdispersion);
+
+    let mut lines = Vec::with_capacity(results.len() + 1);
+    lines.push(SiteTestResult::tsv_header());
+    lines.extend(results.iter().map(|x| x.to_tsv()));
+
+    let output = format!("{}.tests.tsv.gz", args.output);
+    write_lines(&lines, &output)?;
+    println!("Wrote {} test results to {}", results.len(), output);
+
+    if let Some(format) = args.vcf {
+        let contigs = read_contigs(&args.fg_bam)?;
+        let output = format.file_name(&args.output);
+        write_vcf(&sites, &results, &contigs, format, &output)?;
+        println!("Wrote the sites to {}", output);
+    }
+    Ok(())
+}
@@ -1,19 +1,29 @@
+use crate::sift::report::{FoundOn, SiftedSite};
+use crate::util::error::{is_faba_error, FabaError};
+use crate::util::fasta::FastaReader;
 use crate::util::misc::make_intervals;
+use crate::util::regions::RegionFilters;
 
 use rayon::prelude::*;
-use rust_htslib::bam::{self, Read};
-use std::collections::{HashMap, HashSet};
-use std::sync::{Arc, Mutex};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Mutex;
 
 use crate::sift::*;
 
+/// Sift through two or more BAM files jointly.  They are visited
+/// block by block in lockstep, variable positions are decided by the
+/// counts combined across the files, and only the statistics of these
+/// positions are kept, all in a single pass.
+///
 pub struct BamSifter {
-    bam_reader: bam::IndexedReader,
+    bam_readers: Vec<BamReaderPool>,
+    /// contigs shared by all the BAM files with their lengths
+    contigs: Vec<(Box<str>, i64)>,
     jobs: Vec<(Box<str>, Vec<(i64, i64)>)>,
-    forward_variable_map: HashMap<Box<str>, HashSet<i64>>,
-    reverse_variable_map: HashMap<Box<str>, HashSet<i64>>,
-    forward_stat: HashMap<(BamSample, Box<str>), Vec<DnaBaseStat>>,
-    reverse_stat: HashMap<(BamSample, Box<str>), Vec<DnaBaseStat>>,
+    block_size: i64,
+    read_filters: ReadFilters,
+    base_filters: rules::BaseFilters,
+    reference: Option<Mutex<FastaReader>>,
 }
 
 #[allow(dead_code)]
@@ -21,172 +31,314 @@ impl BamSifter {
     ///
     /// create a wrapper for BAM file sifting routines
     ///
-    /// * `bam_file` - alignment file name
-    /// * `index_file` - index file name
+    /// * `bam_files` - pairs of alignment (BAM or CRAM) and index file
+    ///   names; CRAM files need `set_reference`
+    /// * `block_size` - genomic block size for parallel sweeps
+    /// * `read_filters` - filters on reads and bases
     ///
-    pub fn from_file(bam_file: &str, index_file: &str, block_size: Option<usize>) -> Self {
+    pub fn from_files(
+        bam_files: &[(&str, &str)],
+        block_size: Option<usize>,
+        read_filters: &ReadFilters,
+    ) -> anyhow::Result<Self> {
         //
         let block_size = match block_size {
+            Some(0) => return Err(anyhow::anyhow!("block size must be positive")),
             Some(x) => x as i64,
             _ => 10_000i64,
         };
 
+        if bam_files.is_empty() {
+            return Err(anyhow::anyhow!("no BAM files to sift through"));
+        }
+
         //
-        // read header information
+        // read header information: only the contigs shared by all
+        // the BAM files can be visited in lockstep
         //
-        let br = bam::Reader::from_path(bam_file)
-            .expect(&format!("failed to initialize BAM file: {}", bam_file));
+        let mut contigs: Vec<(Box<str>, i64)> = vec![];
+        let mut shared: HashSet<Box<str>> = HashSet::new();
 
-        let hdr = br.header();
-
-        let mut chr_interval_jobs = vec![];
+        for (i, (bam_file, _)) in bam_files.iter().enumerate() {
+            let bam_contigs = read_contigs(bam_file)?;
+            let names: HashSet<Box<str>> = bam_contigs.iter().map(|x| x.0.clone()).collect();
+            if i == 0 {
+                contigs = bam_contigs;
+            }
 
-        for (tid, name) in hdr.target_names().iter().enumerate() {
-            let max_size = hdr.target_len(tid as u32).unwrap() as i64;
-            let name_ = String::from_utf8(name.to_vec()).unwrap();
-            let chr_name = name_.into_boxed_str();
-            chr_interval_jobs.push((chr_name, make_intervals(max_size, block_size)));
+            shared = match i {
+                0 => names,
+                _ => shared.intersection(&names).cloned().collect(),
+            };
         }
 
-        BamSifter {
-            bam_reader: bam::IndexedReader::from_path_and_index(bam_file, &index_file)
-                .expect("failed to create indexed reader"),
+        contigs.retain(|(chr, _)| shared.contains(chr));
+
+        let chr_interval_jobs: Vec<_> = contigs
+            .iter()
+            .map(|(chr, max_size)| (chr.clone(), make_intervals(*max_size, block_size)))
+            .collect();
+
+        let bam_readers = bam_files
+            .iter()
+            .map(|(bam_file, index_file)| BamReaderPool::new(bam_file, index_file))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(BamSifter {
+            bam_readers,
+            contigs,
             jobs: chr_interval_jobs,
-            forward_variable_map: HashMap::new(),
-            reverse_variable_map: HashMap::new(),
-            forward_stat: HashMap::new(),
-            reverse_stat: HashMap::new(),
-        }
+            block_size,
+            read_filters: read_filters.clone(),
+            base_filters: rules::BaseFilters::new(),
+            reference: None,
+        })
+    }
+
+    /// Number of BAM files sifted jointly
+    pub fn num_bams(&self) -> usize {
+        self.bam_readers.len()
     }
 
-    /// Sweep all the blocks to identify variable positions. This will
-    /// fill in the found variable positions in forward_variable_map
-    /// and reverse_variable_map.
+    /// Restrict the sweep to the selected regions of the shared
+    /// contigs, e.g., a gene panel, instead of the whole genome
     ///
-    pub fn sweep_variable_positions(&mut self) {
-        for (chr, blocks) in self.jobs.iter() {
-            let fvar_set = self
-                .forward_variable_map
-                .entry(chr.clone())
-                .or_insert(HashSet::new());
-
-            let rvar_set = self
-                .reverse_variable_map
-                .entry(chr.clone())
-                .or_insert(HashSet::new());
-
-            let forward_arc = Arc::new(Mutex::new(fvar_set));
-            let reverse_arc = Arc::new(Mutex::new(rvar_set));
-
-            let bam_arc = Arc::new(Mutex::new(&mut self.bam_reader));
-
-            blocks.iter().par_bridge().for_each(|(lb, ub)| {
-                let region = (chr.as_ref(), *lb, *ub);
-                let base_filter = rules::BaseFilters::new();
-                let mut forward = vec![];
-                let mut reverse = vec![];
-
-                if let Ok(freq_map) = get_dna_base_freq(&bam_arc, region) {
-                    for samp in freq_map.samples() {
-                        // forward direction : 5 -> 3
-                        if let Some(stats) = freq_map.get_forward(samp) {
-                            for bs in stats {
-                                if base_filter.is_variable(bs) {
-                                    forward.push(bs.position());
-                                }
-                            }
-                        }
-                        // reverse direction : 3 -> 5
-                        if let Some(stats) = freq_map.get_reverse(samp) {
-                            for bs in stats {
-                                if base_filter.is_variable(bs) {
-                                    reverse.push(bs.position());
-                                }
-                            }
-                        }
-                    }
-                }
+    /// * `regions` - BED/GFF intervals, contigs and minimum length
+    ///
+    pub fn set_regions(&mut self, regions: &RegionFilters) -> anyhow::Result<()> {
+        let block_size = self.block_size;
 
-                forward_arc
-                    .lock()
-                    .expect("failed to lock forward")
-                    .extend(forward);
-                reverse_arc
-                    .lock()
-                    .expect("failed to lock reverse")
-                    .extend(reverse);
-            });
-        }
+        self.jobs = regions
+            .select(&self.contigs)?
+            .into_iter()
+            .map(|(chr, intervals)| {
+                let blocks = intervals
+                    .into_iter()
+                    .flat_map(|(lb, ub)| {
+                        make_intervals(ub - lb, block_size)
+                            .into_iter()
+                            .map(move |(s, e)| (lb + s, lb + e))
+                    })
+                    .collect();
+                (chr, blocks)
+            })
+            .collect();
+        Ok(())
     }
 
-    /// add (potentially) missed variable positions
-    pub fn add_missed_positions(&mut self, other: &BamSifter) {
-        self.add_forward_positions(other.get_forward_variable_positions());
-        self.add_reverse_positions(other.get_reverse_variable_positions());
+    /// Number of blocks to visit
+    pub fn num_blocks(&self) -> usize {
+        self.jobs.iter().map(|(_, blocks)| blocks.len()).sum()
     }
 
-    fn add_forward_positions(&mut self, new_pos_to_add: &HashMap<Box<str>, HashSet<i64>>) {
-        for (k, new_pos_set) in new_pos_to_add {
-            if let Some(pos_set) = self.forward_variable_map.get_mut(k) {
-                pos_set.extend(new_pos_set);
-            }
+    /// Set up filters to decide variable positions, e.g., with
+    /// conversion rules
+    pub fn set_base_filters(&mut self, base_filters: rules::BaseFilters) {
+        self.base_filters = base_filters;
+    }
+
+    /// Decompress BAM blocks with extra htslib threads in each reader
+    pub fn set_bam_threads(&mut self, threads: usize) -> anyhow::Result<()> {
+        for bam_readers in self.bam_readers.iter_mut() {
+            bam_readers.set_threads(threads)?;
         }
+        Ok(())
     }
 
-    fn add_reverse_positions(&mut self, new_pos_to_add: &HashMap<Box<str>, HashSet<i64>>) {
-        for (k, new_pos_set) in new_pos_to_add {
-            if let Some(pos_set) = self.reverse_variable_map.get_mut(k) {
-                pos_set.extend(new_pos_set);
+    /// Call sites relative to the reference genome, which also
+    /// decodes CRAM files.  Fails if any contig to visit is missing in
+    /// the reference or differs in length, e.g., from another assembly,
+    /// so set the regions first.
+    ///
+    /// * `fasta_file` - indexed FASTA file (plain or bgzipped)
+    ///
+    pub fn set_reference(&mut self, fasta_file: &str) -> anyhow::Result<()> {
+        let fasta = FastaReader::from_path(fasta_file)?;
+        let missing: Vec<&str> = self
+            .jobs
+            .iter()
+            .map(|(chr, _)| chr.as_ref())
+            .filter(|chr| !fasta.has_seq(chr))
+            .collect();
+        if !missing.is_empty() {
+            return Err(anyhow::anyhow!(
+                "contigs not found in the reference {}: {}",
+                fasta_file,
+                missing.join(",")
+            ));
+        }
+        let visited: HashSet<&str> = self.jobs.iter().map(|(chr, _)| chr.as_ref()).collect();
+        for (chr, len) in self.contigs.iter() {
+            let fasta_len = fasta.seq_len(chr);
+            if visited.contains(chr.as_ref()) && fasta_len != Some(*len) {
+                return Err(anyhow::anyhow!(
+                    "contig {} has {} bp in the BAM files but {} bp in the reference {}",
+                    chr,
+                    len,
+                    fasta_len.unwrap_or(0),
+                    fasta_file
+                ));
             }
         }
+        for bam_readers in self.bam_readers.iter_mut() {
+            bam_readers.set_reference(fasta_file)?;
+        }
+        self.reference = Some(Mutex::new(fasta));
+        Ok(())
     }
 
-    pub fn get_forward_variable_positions(&self) -> &HashMap<Box<str>, HashSet<i64>> {
-        &self.forward_variable_map
+    /// Sweep all the blocks of all the BAM files together, and
+    /// collect the statistics of the variable positions on both
+    /// strands.  Sites are sorted by (chr, pos, strand, sample), with
+    /// the contigs in the order of the BAM header, and their
+    /// statistics are in the order of the BAM files.  Empty
+    /// blocks are skipped, but any other failure stops the sweep.
+    ///
+    pub fn sift(&self) -> anyhow::Result<Vec<SiftedSite>> {
+        let mut ret: Vec<SiftedSite> = vec![];
+        for (chr, blocks) in self.jobs.iter() {
+            let sites = blocks
+                .par_iter()
+                .map(|&(lb, ub)| self.sift_block((chr.as_ref(), lb, ub)))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            let mut sites: Vec<SiftedSite> = sites.into_iter().flatten().collect();
+            sites.sort_by(|x, y| (x.pos, x.strand, &x.sample).cmp(&(y.pos, y.strand, &y.sample)));
+            ret.extend(sites);
+        }
+        Ok(ret)
     }
 
-    pub fn get_reverse_variable_positions(&self) -> &HashMap<Box<str>, HashSet<i64>> {
-        &self.reverse_variable_map
-    }
+    /// Count bases of a block in every BAM file and keep the
+    /// variable positions
+    fn sift_block(&self, region: (&str, i64, i64)) -> anyhow::Result<Vec<SiftedSite>> {
+        let nbams = self.bam_readers.len();
 
-    /// Populate statistics. This will accumulate sufficient
-    /// statistics of the variable positions previously found by
-    /// [`sweep_variable_positions`].
-    ///
-    pub fn populate_statistics(&mut self) {
-        let fstat_arc = Arc::new(Mutex::new(&mut self.forward_stat));
-        let rstat_arc = Arc::new(Mutex::new(&mut self.reverse_stat));
-        let bam_arc = Arc::new(Mutex::new(&mut self.bam_reader));
-
-        for (chr, positions) in self.forward_variable_map.iter() {
-            positions.iter().par_bridge().for_each(|x| {
-                let _chr = chr.as_ref();
-                let _bp = *x;
-                let region = (_chr, _bp, _bp + 1);
-
-                let mut fstat = fstat_arc.lock().expect("unable to lock fstat");
-                let mut rstat = rstat_arc.lock().expect("unable to lock rstat");
-
-                if let Ok(freq_map) = get_dna_base_freq(&bam_arc, region) {
-                    for samp in freq_map.samples() {
-                        let fstat_vec = fstat.entry((samp.clone(), chr.clone())).or_insert(vec![]);
-
-                        if let Some(statvec) = freq_map.get_forward(samp) {
-                            for bs in statvec {
-                                fstat_vec.push(bs.clone());
-                            }
+        // the same reference bases apply to all the BAM files
+        let seq = fetch_reference(&self.reference, region)?;
+
+        let mut freq_maps: Vec<Option<DnaStatMap>> = Vec::with_capacity(nbams);
+        for bam_readers in self.bam_readers.iter() {
+            let freq_map = bam_readers.with_reader(|bam_reader| {
+                get_dna_base_freq(bam_reader, region, &self.read_filters)
+            });
+            match freq_map {
+                Ok(mut freq_map) => {
+                    if let Some(seq) = seq.as_deref() {
+                        freq_map.set_reference(region.1, seq);
+                    }
+                    freq_maps.push(Some(freq_map));
+                }
+                Err(e) if is_faba_error(&e, &FabaError::EmptyRegion) => freq_maps.push(None),
+                Err(e) => return Err(e),
+            }
+        }
+
+        // variable positions by the counts combined across the BAM
+        // files, within each sample and over all the samples
+        let mut forward = HashSet::new();
+        let mut reverse = HashSet::new();
+
+        for (strand, variable) in [
+            (DnaStrand::Forward, &mut forward),
+            (DnaStrand::Reverse, &mut reverse),
+        ] {
+            let mut pooled: HashMap<(Option<&BamSample>, i64), DnaBaseStat> = HashMap::new();
+            for freq_map in freq_maps.iter().flatten() {
+                for samp in freq_map.samples() {
+                    for bs in strand_stats(freq_map, samp, strand) {
+                        for key in [(Some(samp), bs.position()), (None, bs.position())] {
+                            pooled
+                                .entry(key)
+                                .or_insert_with(|| DnaBaseStat::new(bs.position()))
+                                .add_stat(bs);
                         }
+                    }
+                }
+            }
+            variable.extend(
+                pooled
+                    .values()
+                    .filter(|bs| self.base_filters.is_variable(bs, strand))
+                    .map(|bs| bs.position()),
+            );
+        }
+
+        if forward.is_empty() && reverse.is_empty() {
+            return Ok(vec![]);
+        }
 
-                        let rstat_vec = rstat.entry((samp.clone(), chr.clone())).or_insert(vec![]);
+        // keep both strands at the union of variable positions
+        let mut sites: BTreeMap<(i64, DnaStrand, BamSample), Vec<DnaBaseStat>> = BTreeMap::new();
 
-                        if let Some(statvec) = freq_map.get_reverse(samp) {
-                            for bs in statvec {
-                                rstat_vec.push(bs.clone());
-                            }
+        for (i, freq_map) in freq_maps.iter().enumerate() {
+            let Some(freq_map) = freq_map else {
+                continue;
+            };
+            for samp in freq_map.samples() {
+                for strand in [DnaStrand::Forward, DnaStrand::Reverse] {
+                    for bs in strand_stats(freq_map, samp, strand) {
+                        let pos = bs.position();
+                        if !forward.contains(&pos) && !reverse.contains(&pos) {
+                            continue;
                         }
+                        let stats = sites
+                            .entry((pos, strand, samp.clone()))
+                            .or_insert_with(|| vec![DnaBaseStat::new(pos); nbams]);
+                        stats[i] = bs.clone();
                     }
                 }
-            });
+            }
         }
+
+        let (chr, _, _) = region;
+
+        Ok(sites
+            .into_iter()
+            .filter(|(_, stats)| stats.iter().any(|bs| bs.total() > 0_f32))
+            .map(|((pos, strand, sample), stats)| {
+                let found_on = match (forward.contains(&pos), reverse.contains(&pos)) {
+                    (true, true) => FoundOn::Both,
+                    (true, false) => FoundOn::Forward,
+                    _ => FoundOn::Reverse,
+                };
+                SiftedSite {
+                    chr: chr.into(),
+                    pos,
+                    strand,
+                    sample,
+                    found_on,
+                    stats,
+                }
+            })
+            .collect())
     }
 }
+
+/// Statistics of a sample on the strand
+fn strand_stats<'a>(
+    freq_map: &'a DnaStatMap,
+    samp: &BamSample,
+    strand: DnaStrand,
+) -> impl Iterator<Item = &'a DnaBaseStat> {
+    let stats = match strand {
+        DnaStrand::Forward => freq_map.get_forward(samp),
+        DnaStrand::Reverse => freq_map.get_reverse(samp),
+    };
+    stats.into_iter().flatten()
+}
+
+/// Reference bases of the region, if the reference genome is available
+fn fetch_reference(
+    reference: &Option<Mutex<FastaReader>>,
+    region: (&str, i64, i64),
+) -> anyhow::Result<Option<Vec<u8>>> {
+    let Some(fasta) = reference else {
+        return Ok(None);
+    };
+    let (chr, lb, ub) = region;
+    let seq = fasta
+        .lock()
+        .map_err(|_| FabaError::PoisonedLock("the reference".into()))?
+        .fetch(chr, lb, ub)?;
+    Ok(Some(seq))
+}
@@ -0,0 +1,326 @@
+use crate::sift::report::{group_sites, pooled_ref_alt, CaseControlSite};
+use crate::sift::*;
+
+use clap::ValueEnum;
+use rayon::prelude::*;
+use statrs::function::erf::erfc;
+use statrs::function::gamma::ln_gamma;
+
+/// Statistical tests for differential base composition
+///
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum SiteTest {
+    /// Fisher's exact test on the 2x2 table pooled over samples
+    Fisher,
+    /// Beta-binomial likelihood-ratio test with a fixed
+    /// overdispersion (zero for a binomial test)
+    BetaBinomial,
+    /// Beta-binomial likelihood-ratio test with the overdispersion
+    /// estimated from samples (or barcodes) within each site
+    Overdispersed,
+}
+
+/// Test result for a candidate site, pooling all the samples
+///
+pub struct SiteTestResult {
+    pub chr: Box<str>,
+    pub pos: i64,
+    pub strand: DnaStrand,
+    pub reference: Dna,
+    pub alternate: Dna,
+    /// (ref, alt) counts in case
+    pub case: (f32, f32),
+    /// (ref, alt) counts in control
+    pub control: (f32, f32),
+    /// log-odds ratio of the alternate allele, case vs. control
+    pub log_odds: f64,
+    pub pvalue: f64,
+    pub qvalue: f64,
+}
+
+#[allow(dead_code)]
+impl SiteTestResult {
+    pub fn tsv_header() -> Box<str> {
+        "chr\tpos\tstrand\tref\talt\tcase_ref\tcase_alt\tcontrol_ref\tcontrol_alt\tlog_odds\tpvalue\tqvalue"
+            .into()
+    }
+
+    /// a tab-separated line with 1-based position
+    pub fn to_tsv(&self) -> Box<str> {
+        format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            self.chr,
+            self.pos + 1,
+            self.strand,
+            self.reference,
+            self.alternate,
+            self.case.0,
+            self.case.1,
+            self.control.0,
+            self.control.1,
+            self.log_odds,
+            self.pvalue,
+            self.qvalue
+        )
+        .into_boxed_str()
+    }
+}
+
+/// Test every candidate site for differential allele frequencies
+/// between case and control, and adjust p-values by the
+/// Benjamini-Hochberg procedure.  Alternate alleles are called on the
+/// counts combined over all the samples and both conditions, and so
+/// are the reference alleles if the reference genome is unknown.
+///
+/// * `sites` - sorted by (chr, pos, strand, sample) as in
+///   [`crate::sift::report::collect_case_control_sites`]
+/// * `method` - statistical test
+/// * `overdispersion` - fixed overdispersion for
+///   [`SiteTest::BetaBinomial`]
+///
+pub fn test_case_control_sites(
+    sites: &[CaseControlSite],
+    method: SiteTest,
+    overdispersion: f64,
+) -> Vec<SiteTestResult> {
+    let groups = group_sites(sites);
+
+    let mut results: Vec<SiteTestResult> = groups
+        .par_iter()
+        .map(|group| test_site(group, method, overdispersion))
+        .collect();
+
+    let pvalues: Vec<f64> = results.iter().map(|x| x.pvalue).collect();
+    for (res, q) in results.iter_mut().zip(bh_adjust(&pvalues)) {
+        res.qvalue = q;
+    }
+    results
+}
+
+fn test_site(group: &[CaseControlSite], method: SiteTest, overdispersion: f64) -> SiteTestResult {
+    let first = &group[0];
+
+    let BiAllele { a1, a2, .. } = pooled_ref_alt(group);
+
+    let count = |stat: &DnaBaseStat| (stat.get(a1) as f64, stat.get(a2) as f64);
+    let case: Vec<(f64, f64)> = group.iter().map(|s| count(&s.case)).collect();
+    let control: Vec<(f64, f64)> = group.iter().map(|s| count(&s.control)).collect();
+
+    let sum = |xx: &[(f64, f64)]| {
+        xx.iter()
+            .fold((0_f64, 0_f64), |acc, x| (acc.0 + x.0, acc.1 + x.1))
+    };
+    let (case_ref, case_alt) = sum(&case);
+    let (control_ref, control_alt) = sum(&control);
+
+    let log_odds = ((case_alt + 0.5) / (case_ref + 0.5)).ln()
+        - ((control_alt + 0.5) / (control_ref + 0.5)).ln();
+
+    let pvalue = match method {
+        SiteTest::Fisher => fisher_exact(
+            case_ref.round() as u64,
+            case_alt.round() as u64,
+            control_ref.round() as u64,
+            control_alt.round() as u64,
+        ),
+        SiteTest::BetaBinomial => beta_binomial_lrt(&case, &control, Some(overdispersion)),
+        SiteTest::Overdispersed => beta_binomial_lrt(&case, &control, None),
+    };
+
+    SiteTestResult {
+        chr: first.chr.clone(),
+        pos: first.pos,
+        strand: first.strand,
+        reference: a1,
+        alternate: a2,
+        case: (case_ref as f32, case_alt as f32),
+        control: (control_ref as f32, control_alt as f32),
+        log_odds,
+        pvalue,
+        qvalue: 1_f64,
+    }
+}
+
+/// Two-sided Fisher's exact test for a 2x2 table [[a, b], [c, d]]
+///
+pub fn fisher_exact(a: u64, b: u64, c: u64, d: u64) -> f64 {
+    let (r1, c1, n) = (a + b, a + c, a + b + c + d);
+    if n == 0 {
+        return 1_f64;
+    }
+
+    let ln_fact = |x: u64| ln_gamma(x as f64 + 1_f64);
+    let ln_const = ln_fact(r1) + ln_fact(n - r1) + ln_fact(c1) + ln_fact(n - c1) - ln_fact(n);
+
+    // hypergeometric probability of the top-left cell
+    let ln_pmf = |x: u64| {
+        ln_const - ln_fact(x) - ln_fact(r1 - x) - ln_fact(c1 - x) - ln_fact(n + x - r1 - c1)
+    };
+
+    let lb = (r1 + c1).saturating_sub(n);
+    let ub = r1.min(c1);
+    let observed = ln_pmf(a);
+
+    let pvalue: f64 = (lb..=ub)
+        .map(ln_pmf)
+        .filter(|&lp| lp <= observed + 1e-7)
+        .map(f64::exp)
+        .sum();
+
+    pvalue.min(1_f64)
+}
+
+/// Likelihood-ratio test for the alternate allele frequencies being
+/// different between case and control, treating samples as
+/// replicates with beta-binomial overdispersion.
+///
+/// * `case` - (ref, alt) counts of the case samples
+/// * `control` - (ref, alt) counts of the control samples
+/// * `rho` - fixed overdispersion; `None` to estimate it under each
+///   hypothesis
+///
+pub fn beta_binomial_lrt(case: &[(f64, f64)], control: &[(f64, f64)], rho: Option<f64>) -> f64 {
+    let pooled: Vec<(f64, f64)> = case.iter().chain(control.iter()).cloned().collect();
+
+    let fit = |groups: &[&[(f64, f64)]]| -> f64 {
+        match rho {
+            Some(rho) => groups.iter().map(|g| max_llik_mean(g, rho)).sum(),
+            None => {
+                let (_, llik) = golden_section_max(1e-6, 0.5, |r| {
+                    groups.iter().map(|g| max_llik_mean(g, r)).sum()
+                });
+                llik
+            }
+        }
+    };
+
+    let llik0 = fit(&[&pooled]);
+    let llik1 = fit(&[case, control]);
+
+    chisq1_sf(2_f64 * (llik1 - llik0).max(0_f64))
+}
+
+/// maximize log-likelihood over the mean frequency of the alternate
+/// allele with the overdispersion fixed
+fn max_llik_mean(counts: &[(f64, f64)], rho: f64) -> f64 {
+    let (_, llik) = golden_section_max(1e-6, 1_f64 - 1e-6, |mu| {
+        counts
+            .iter()
+            .map(|&(n1, n2)| beta_binomial_llik(n2, n1 + n2, mu, rho))
+            .sum()
+    });
+    llik
+}
+
+/// log-likelihood of k out of n with mean `mu` and overdispersion
+/// `rho` (intra-class correlation) up to a constant
+fn beta_binomial_llik(k: f64, n: f64, mu: f64, rho: f64) -> f64 {
+    if n <= 0_f64 {
+        return 0_f64;
+    }
+    if rho <= 1e-8 {
+        return k * mu.ln() + (n - k) * (1_f64 - mu).ln();
+    }
+    let s = (1_f64 - rho) / rho;
+    let (a, b) = (mu * s, (1_f64 - mu) * s);
+    ln_gamma(k + a) + ln_gamma(n - k + b) - ln_gamma(n + s) - ln_gamma(a) - ln_gamma(b)
+        + ln_gamma(s)
+}
+
+/// golden-section search for the maximum of a unimodal function
+fn golden_section_max<F>(lb: f64, ub: f64, func: F) -> (f64, f64)
+where
+    F: Fn(f64) -> f64,
+{
+    const GR: f64 = 0.618_033_988_749_895;
+    const TOL: f64 = 1e-5;
+    const MAX_ITER: usize = 100;
+
+    let (mut a, mut b) = (lb, ub);
+    let mut c = b - GR * (b - a);
+    let mut d = a + GR * (b - a);
+    let (mut fc, mut fd) = (func(c), func(d));
+
+    for _ in 0..MAX_ITER {
+        if (b - a).abs() < TOL {
+            break;
+        }
+        if fc > fd {
+            b = d;
+            d = c;
+            fd = fc;
+            c = b - GR * (b - a);
+            fc = func(c);
+        } else {
+            a = c;
+            c = d;
+            fc = fd;
+            d = a + GR * (b - a);
+            fd = func(d);
+        }
+    }
+
+    if fc > fd {
+        (c, fc)
+    } else {
+        (d, fd)
+    }
+}
+
+/// upper tail of the chi-square distribution with one degree of
+/// freedom
+fn chisq1_sf(x: f64) -> f64 {
+    erfc((x / 2_f64).sqrt())
+}
+
+/// Benjamini-Hochberg adjusted p-values
+///
+pub fn bh_adjust(pvalues: &[f64]) -> Vec<f64> {
+    let m = pvalues.len();
+    let mut order: Vec<usize> = (0..m).collect();
+    order.sort_by(|&i, &j| pvalues[j].total_cmp(&pvalues[i]));
+
+    let mut ret = vec![1_f64; m];
+    let mut running_min = 1_f64;
+    for (r, &i) in order.iter().enumerate() {
+        let rank = (m - r) as f64;
+        running_min = running_min.min(pvalues[i] * m as f64 / rank);
+        ret[i] = running_min;
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fisher_exact_matches_r() {
+        // fisher.test(matrix(c(3, 1, 1, 3), 2))$p.value
+        assert!((fisher_exact(3, 1, 1, 3) - 0.485_714_3).abs() < 1e-6);
+        assert!((fisher_exact(5, 5, 5, 5) - 1_f64).abs() < 1e-9);
+        assert_eq!(fisher_exact(0, 0, 0, 0), 1_f64);
+    }
+
+    #[test]
+    fn bh_adjust_matches_r() {
+        // p.adjust(c(0.01, 0.04, 0.03, 0.2, 0.005), method = "BH")
+        let qvalues = bh_adjust(&[0.01, 0.04, 0.03, 0.2, 0.005]);
+        let expected = [0.025, 0.05, 0.05, 0.2, 0.025];
+        for (q, e) in qvalues.iter().zip(expected) {
+            assert!((q - e).abs() < 1e-12, "{} != {}", q, e);
+        }
+    }
+
+    #[test]
+    fn beta_binomial_lrt_separates_groups() {
+        let same = [(10_f64, 10_f64), (12_f64, 8_f64)];
+        let case = [(50_f64, 0_f64), (48_f64, 2_f64)];
+        let control = [(0_f64, 50_f64), (2_f64, 48_f64)];
+
+        for rho in [Some(0_f64), Some(0.1), None] {
+            assert!(beta_binomial_lrt(&same, &same, rho) > 0.99);
+            assert!(beta_binomial_lrt(&case, &control, rho) < 1e-3);
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use crate::sift::report::{group_sites, pooled_ref_alt, CaseControlSite};
+use crate::sift::test::SiteTestResult;
+use crate::sift::*;
+
+use clap::ValueEnum;
+use rust_htslib::bcf::{self, record::Numeric};
+use std::collections::{BTreeSet, HashMap};
+
+/// Output formats of variant calls
+///
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum VcfFormat {
+    /// bgzipped VCF (`.vcf.gz`)
+    Vcf,
+    /// BCF (`.bcf`)
+    Bcf,
+}
+
+impl VcfFormat {
+    pub fn file_name(&self, header: &str) -> String {
+        match self {
+            VcfFormat::Vcf => format!("{}.vcf.gz", header),
+            VcfFormat::Bcf => format!("{}.bcf", header),
+        }
+    }
+}
+
+const HEADER_LINES: [&str; 9] = [
+    r#"##INFO=<ID=FOUND,Number=1,Type=String,Description="Transcript strands on which the position was variable (+, -, +-)">"#,
+    r#"##INFO=<ID=TALT,Number=2,Type=String,Description="Alternate allele tested on the + and - transcript strands">"#,
+    r#"##INFO=<ID=LOR,Number=2,Type=Float,Description="Log-odds ratio of the alternate allele, case vs. control, on the + and - transcript strands">"#,
+    r#"##INFO=<ID=PVAL,Number=2,Type=Float,Description="P-value of the case vs. control test on the + and - transcript strands">"#,
+    r#"##INFO=<ID=QVAL,Number=2,Type=Float,Description="Benjamini-Hochberg q-value of the case vs. control test on the + and - transcript strands">"#,
+    r#"##FORMAT=<ID=AD,Number=R,Type=Integer,Description="Read depth of the reference and alternate alleles">"#,
+    r#"##FORMAT=<ID=ADF,Number=R,Type=Integer,Description="Read depth of the reference and alternate alleles on the + transcript strand">"#,
+    r#"##FORMAT=<ID=ADR,Number=R,Type=Integer,Description="Read depth of the reference and alternate alleles on the - transcript strand">"#,
+    r#"##FORMAT=<ID=DP,Number=1,Type=Integer,Description="Read depth of all the bases">"#,
+];
+
+/// Write the sifted sites to a VCF/BCF file, one record for each
+/// (chr, pos) in the order of the contigs.  Both transcript strands
+/// of a position share the record: `ADF` and `ADR` split the allele
+/// depths by strand, and the INFO fields `TALT`, `LOR`, `PVAL` and
+/// `QVAL` hold the values of the + and - strands (missing if the
+/// strand was not tested).  ALT lists the alternate alleles of both
+/// strands.  The first two samples, `case` and `control`, pool all
+/// the reads of each BAM file, followed by `case:<barcode>` and
+/// `control:<barcode>` for every cell barcode.  REF and ALT come
+/// from the test results if available, or else from the counts
+/// combined over both conditions.
+///
+/// * `sites` - sorted by (chr, pos, strand, sample)
+/// * `tests` - test results of the sites (can be empty)
+/// * `contigs` - contig names and lengths
+/// * `format` - VCF or BCF
+/// * `output_file` - output file name
+///
+pub fn write_vcf(
+    sites: &[CaseControlSite],
+    tests: &[SiteTestResult],
+    contigs: &[(Box<str>, i64)],
+    format: VcfFormat,
+    output_file: &str,
+) -> anyhow::Result<()> {
+    let barcodes: BTreeSet<&BamSample> = sites
+        .iter()
+        .map(|x| &x.sample)
+        .filter(|x| matches!(x, BamSample::Barcode(_)))
+        .collect();
+
+    let mut header = bcf::Header::new();
+    for (chr, len) in contigs {
+        header.push_record(format!("##contig=<ID={},length={}>", chr, len).as_bytes());
+    }
+    for line in HEADER_LINES {
+        header.push_record(line.as_bytes());
+    }
+    header.push_sample(b"case").push_sample(b"control");
+    for bc in barcodes.iter() {
+        header.push_sample(format!("case:{}", bc).as_bytes());
+        header.push_sample(format!("control:{}", bc).as_bytes());
+    }
+
+    // sample -> column of the case (and the next for control)
+    let columns: HashMap<&BamSample, usize> = barcodes
+        .iter()
+        .enumerate()
+        .map(|(j, &bc)| (bc, 2 * (j + 1)))
+        .collect();
+    let ncols = 2 * (barcodes.len() + 1);
+
+    let tests: HashMap<(&str, i64, DnaStrand), &SiteTestResult> = tests
+        .iter()
+        .map(|x| ((x.chr.as_ref(), x.pos, x.strand), x))
+        .collect();
+
+    let bcf_format = match format {
+        VcfFormat::Vcf => bcf::Format::Vcf,
+        VcfFormat::Bcf => bcf::Format::Bcf,
+    };
+    let mut writer = bcf::Writer::from_path(output_file, &header, false, bcf_format)?;
+
+    // both strands of a position, sorted by (rid, pos)
+    let mut positions: Vec<(u32, i64, Vec<&[CaseControlSite]>)> = vec![];
+    for group in group_sites(sites) {
+        let (chr, pos) = (group[0].chr.as_ref(), group[0].pos);
+        match positions.last_mut() {
+            Some((_, p, groups)) if *p == pos && groups[0][0].chr.as_ref() == chr => {
+                groups.push(group);
+            }
+            _ => {
+                let rid = writer.header().name2rid(chr.as_bytes())?;
+                positions.push((rid, pos, vec![group]));
+            }
+        }
+    }
+    positions.sort_by_key(|(rid, pos, _)| (*rid, *pos));
+
+    for (rid, pos, groups) in positions {
+        let first = &groups[0][0];
+
+        // per strand: stats of the samples, and the tested alleles
+        let mut strands: [Option<(Vec<DnaBaseStat>, Option<&SiteTestResult>)>; 2] = [None, None];
+        let mut alleles: Vec<Dna> = vec![];
+
+        for group in groups {
+            let strand = group[0].strand;
+
+            let mut stats = vec![DnaBaseStat::new(pos); ncols];
+            for s in group {
+                for (k, stat) in [&s.case, &s.control].into_iter().enumerate() {
+                    stats[k].add_stat(stat);
+                    if let Some(&j) = columns.get(&s.sample) {
+                        stats[j + k].add_stat(stat);
+                    }
+                }
+            }
+
+            let test = tests.get(&(first.chr.as_ref(), pos, strand)).copied();
+
+            let (a1, a2) = match test {
+                Some(x) => (x.reference, x.alternate),
+                None => {
+                    let BiAllele { a1, a2, .. } = pooled_ref_alt(group);
+                    (a1, a2)
+                }
+            };
+            for b in [a1, a2] {
+                if !alleles.contains(&b) {
+                    alleles.push(b);
+                }
+            }
+
+            strands[strand_index(strand)] = Some((stats, test));
+        }
+
+        let mut record = writer.empty_record();
+        record.set_rid(Some(rid));
+        record.set_pos(pos);
+        record.set_qual(f32::missing());
+
+        let allele_strings: Vec<String> = alleles.iter().map(|x| x.to_string()).collect();
+        let allele_bytes: Vec<&[u8]> = allele_strings.iter().map(|x| x.as_bytes()).collect();
+        record.set_alleles(&allele_bytes)?;
+
+        record.push_info_string(b"FOUND", &[first.found_on.to_string().as_bytes()])?;
+
+        let tested = |f: &dyn Fn(&SiteTestResult) -> f32| -> Vec<f32> {
+            strands
+                .iter()
+                .map(|x| match x {
+                    Some((_, Some(test))) => f(test),
+                    _ => f32::missing(),
+                })
+                .collect()
+        };
+        if strands.iter().any(|x| matches!(x, Some((_, Some(_))))) {
+            let talt: Vec<String> = strands
+                .iter()
+                .map(|x| match x {
+                    Some((_, Some(test))) => test.alternate.to_string(),
+                    _ => ".".to_string(),
+                })
+                .collect();
+            let talt: Vec<&[u8]> = talt.iter().map(|x| x.as_bytes()).collect();
+            record.push_info_string(b"TALT", &talt)?;
+            record.push_info_float(b"LOR", &tested(&|x| x.log_odds as f32))?;
+            record.push_info_float(b"PVAL", &tested(&|x| x.pvalue as f32))?;
+            record.push_info_float(b"QVAL", &tested(&|x| x.qvalue as f32))?;
+        }
+
+        // allele depths of each sample on one strand (zero if absent)
+        let depths = |strand: usize| -> Vec<i32> {
+            let mut ret = vec![0_i32; ncols * alleles.len()];
+            if let Some((stats, _)) = &strands[strand] {
+                for (j, stat) in stats.iter().enumerate() {
+                    for (k, &b) in alleles.iter().enumerate() {
+                        ret[j * alleles.len() + k] = stat.get(b) as i32;
+                    }
+                }
+            }
+            ret
+        };
+        let (adf, adr) = (depths(0), depths(1));
+        let ad: Vec<i32> = adf.iter().zip(adr.iter()).map(|(x, y)| x + y).collect();
+        let dp: Vec<i32> = (0..ncols)
+            .map(|j| {
+                strands
+                    .iter()
+                    .flatten()
+                    .map(|(stats, _)| stats[j].total() as i32)
+                    .sum()
+            })
+            .collect();
+
+        record.push_format_integer(b"AD", &ad)?;
+        record.push_format_integer(b"ADF", &adf)?;
+        record.push_format_integer(b"ADR", &adr)?;
+        record.push_format_integer(b"DP", &dp)?;
+
+        writer.write(&record)?;
+    }
+
+    Ok(())
+}
+
+/// 0 for the + strand, 1 for the - strand
+fn strand_index(strand: DnaStrand) -> usize {
+    match strand {
+        DnaStrand::Forward => 0,
+        DnaStrand::Reverse => 1,
+    }
+}
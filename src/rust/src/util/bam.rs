@@ -1,13 +1,20 @@
-use rust_htslib::bam::{self, Read};
+use crate::util::error::{check_file_exists, FabaError};
+use crate::util::file::read_lines;
+
+use clap::{Args, ValueEnum};
+use rust_htslib::bam::{self, record::Aux, Read};
+use std::collections::HashSet;
 use std::hash::Hash;
 use std::path::Path;
+use std::sync::{Arc, Mutex};
 use std::thread;
 
 /// BAM file sample name
 ///
-#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
 #[allow(dead_code)]
 pub enum BamSample {
+    /// reads without a cell barcode, shown as `.`
     Combined,
     Barcode(Box<str>),
 }
@@ -24,27 +31,676 @@ impl std::fmt::Display for BamSample {
     }
 }
 
-/// Check random access BAM index
+const DEFAULT_MIN_MAPQ: u8 = 20;
+const DEFAULT_MIN_BASE_QUALITY: u8 = 20;
+/// unmapped, secondary, QC-fail, duplicate, supplementary
+const DEFAULT_EXCLUDE_FLAGS: u16 = 0xF04;
+/// duplicate
+const DUPLICATE_FLAG: u16 = 0x400;
+const DEFAULT_TRIM_ENDS: usize = 0;
+const DEFAULT_UMI_TAG: &str = "UB";
+const DEFAULT_CELL_TAG: &str = "CB";
+
+/// Sequencing protocols, deciding which strand of the genome each
+/// read came from as RNA
+///
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum LibraryType {
+    /// No strand information: take the read orientation as it is
+    #[default]
+    Unstranded,
+    /// dUTP, NSR, NNSR: read 1 (or single-end reads) antisense, read
+    /// 2 sense to the transcript
+    FrFirststrand,
+    /// Ligation, standard SOLiD: read 1 (or single-end reads) sense,
+    /// read 2 antisense to the transcript
+    FrSecondstrand,
+    /// 10x Genomics 3' gene expression: aligned cDNA reads are sense
+    /// to the transcript
+    #[value(name = "10x-3p")]
+    TenX3p,
+    /// 10x Genomics 5' gene expression: read 1 of paired-end reads
+    /// sense, read 2 (or single-end reads) antisense to the transcript
+    #[value(name = "10x-5p")]
+    TenX5p,
+}
+
+#[allow(dead_code)]
+impl LibraryType {
+    /// Is the originating RNA on the reverse strand of the genome?
+    pub fn is_reverse(&self, rec: &bam::Record) -> bool {
+        rec.is_reverse() ^ self.flips(mate_of(rec))
+    }
+
+    /// Are reads of this mate (0: single-end, 1: read 1, 2: read 2)
+    /// antisense to their transcripts?
+    pub fn flips(&self, mate: usize) -> bool {
+        match self {
+            LibraryType::Unstranded => false,
+            LibraryType::FrFirststrand => mate != 2,
+            LibraryType::FrSecondstrand | LibraryType::TenX3p => mate == 2,
+            LibraryType::TenX5p => mate != 1,
+        }
+    }
+}
+
+impl std::fmt::Display for LibraryType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.to_possible_value() {
+            Some(x) => write!(f, "{}", x.get_name()),
+            None => write!(f, "{:?}", self),
+        }
+    }
+}
+
+/// Which mate is this record?  0: single-end, 1: read 1, 2: read 2
+pub fn mate_of(rec: &bam::Record) -> usize {
+    match (rec.is_paired(), rec.is_first_in_template()) {
+        (false, _) => 0,
+        (true, true) => 1,
+        _ => 2,
+    }
+}
+
+/// A set of cell barcodes to keep
+///
+#[derive(Debug, Clone)]
+pub struct Whitelist(Arc<HashSet<Box<str>>>);
+
+#[allow(dead_code)]
+impl Whitelist {
+    /// Read barcodes in the first column of a (gzipped) file, e.g.,
+    /// `barcodes.tsv.gz` of Cell Ranger
+    pub fn from_file(whitelist_file: &str) -> anyhow::Result<Self> {
+        let barcodes: HashSet<Box<str>> = read_lines(whitelist_file)?
+            .iter()
+            .filter_map(|x| x.split('\t').next())
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty())
+            .map(|x| x.into())
+            .collect();
+
+        if barcodes.is_empty() {
+            return Err(anyhow::anyhow!("no barcodes found in {}", whitelist_file));
+        }
+        Ok(Whitelist(Arc::new(barcodes)))
+    }
+
+    /// Check the barcode as it is, and then without the `-1`-like
+    /// GEM well suffix
+    pub fn contains(&self, barcode: &str) -> bool {
+        self.0.contains(barcode)
+            || barcode
+                .rsplit_once('-')
+                .is_some_and(|(bc, _)| self.0.contains(bc))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+fn parse_whitelist(whitelist_file: &str) -> Result<Whitelist, String> {
+    Whitelist::from_file(whitelist_file).map_err(|e| e.to_string())
+}
+
+/// Filters on BAM records and their aligned bases
+///
+#[derive(Args, Debug, Clone)]
+pub struct ReadFilters {
+    /// Minimum mapping quality of reads
+    #[arg(long, default_value_t = DEFAULT_MIN_MAPQ)]
+    pub min_mapq: u8,
+    /// Minimum Phred base quality
+    #[arg(long, default_value_t = DEFAULT_MIN_BASE_QUALITY)]
+    pub min_base_quality: u8,
+    /// Exclude reads with any of these flags (default: 3844 = 0xF04,
+    /// unmapped, secondary, QC-fail, duplicate, supplementary; 2820 =
+    /// 0xB04 keeping duplicates of reads with a UMI in --umi-tag)
+    #[arg(long)]
+    pub exclude_flags: Option<u16>,
+    /// Ignore this many bases at both ends of the aligned part of
+    /// reads
+    #[arg(long, default_value_t = DEFAULT_TRIM_ENDS)]
+    pub trim_ends: usize,
+    /// Count reads sharing the same UMI in this tag once per cell and
+    /// position by majority vote (empty to count every read).
+    /// Duplicate-flagged reads with a UMI are kept for the vote unless
+    /// --exclude-flags is given
+    #[arg(long, default_value = DEFAULT_UMI_TAG)]
+    pub umi_tag: Box<str>,
+    /// Tag for cell barcodes or samples, e.g., CB, CR, RG, BC (empty
+    /// to combine all the reads)
+    #[arg(long, default_value = DEFAULT_CELL_TAG)]
+    pub cell_tag: Box<str>,
+    /// File of cell barcodes to keep (first column)
+    #[arg(long, value_parser = parse_whitelist)]
+    pub whitelist: Option<Whitelist>,
+    /// Count reads of barcodes not in the whitelist toward the sample
+    /// `.` of reads without a cell barcode instead of dropping them
+    #[arg(long, default_value_t = false)]
+    pub unlisted_to_combined: bool,
+    /// Library type to assign reads to their transcript strands
+    #[arg(long, value_enum, default_value = "unstranded")]
+    pub library_type: LibraryType,
+}
+
+impl Default for ReadFilters {
+    fn default() -> Self {
+        ReadFilters {
+            min_mapq: DEFAULT_MIN_MAPQ,
+            min_base_quality: DEFAULT_MIN_BASE_QUALITY,
+            exclude_flags: None,
+            trim_ends: DEFAULT_TRIM_ENDS,
+            umi_tag: DEFAULT_UMI_TAG.into(),
+            cell_tag: DEFAULT_CELL_TAG.into(),
+            whitelist: None,
+            unlisted_to_combined: false,
+            library_type: LibraryType::default(),
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl ReadFilters {
+    /// Flags to exclude from this record: the given ones, or the
+    /// default without the duplicate flag for reads with a UMI, whose
+    /// duplicates are collapsed by the UMI vote instead
+    pub fn exclude_flags(&self, rec: &bam::Record) -> u16 {
+        match self.exclude_flags {
+            Some(flags) => flags,
+            None if !self.umi_tag.is_empty() && rec.aux(self.umi_tag.as_bytes()).is_ok() => {
+                DEFAULT_EXCLUDE_FLAGS & !DUPLICATE_FLAG
+            }
+            None => DEFAULT_EXCLUDE_FLAGS,
+        }
+    }
+
+    /// Does this record pass the mapping quality and flag filters?
+    pub fn pass_read(&self, rec: &bam::Record) -> bool {
+        rec.flags() & self.exclude_flags(rec) == 0 && rec.mapq() >= self.min_mapq
+    }
+
+    /// Which sample does this record belong to?  Reads without the
+    /// cell tag go to the combined sample, and those with barcodes
+    /// not in the whitelist are dropped (`None`) unless
+    /// `unlisted_to_combined` is set.
+    pub fn sample_of(&self, rec: &bam::Record) -> Option<BamSample> {
+        if self.cell_tag.is_empty() {
+            return Some(BamSample::Combined);
+        }
+
+        // https://docs.rs/rust-htslib/0.47.0/rust_htslib/bam/record/enum.Aux.html
+        let Ok(Aux::String(cb)) = rec.aux(self.cell_tag.as_bytes()) else {
+            return Some(BamSample::Combined);
+        };
+
+        match &self.whitelist {
+            Some(whitelist) if !whitelist.contains(cb) => match self.unlisted_to_combined {
+                true => Some(BamSample::Combined),
+                _ => None,
+            },
+            _ => Some(BamSample::Barcode(cb.into())),
+        }
+    }
+
+    /// The range of read positions `[lb, ub)` to count bases, after
+    /// soft-clipped and trimmed bases are removed
+    pub fn base_range(&self, rec: &bam::Record) -> (usize, usize) {
+        let cigar = rec.cigar();
+        let lb = cigar.leading_softclips() as usize + self.trim_ends;
+        let ub = (rec.seq_len() as i64 - cigar.trailing_softclips()).max(0) as usize;
+        (lb, ub.saturating_sub(self.trim_ends))
+    }
+
+    /// Does the base at the read position pass the quality filter?
+    pub fn pass_base(&self, rec: &bam::Record, rpos: usize) -> bool {
+        rec.qual()
+            .get(rpos)
+            .is_some_and(|&q| q >= self.min_base_quality)
+    }
+}
+
+/// Is this a CRAM file?
+pub fn is_cram(bam_file: &str) -> bool {
+    Path::new(bam_file)
+        .extension()
+        .is_some_and(|x| x.eq_ignore_ascii_case("cram"))
+}
+
+/// CRAM files can only be decoded with the reference genome
+pub fn check_cram_reference(bam_file: &str, reference: Option<&str>) -> anyhow::Result<()> {
+    if is_cram(bam_file) && reference.is_none() {
+        return Err(anyhow::anyhow!(
+            "the reference FASTA file is required to read {}",
+            bam_file
+        ));
+    }
+    Ok(())
+}
+
+/// A pool of indexed BAM (or CRAM) readers, one for each rayon
+/// worker, so that parallel jobs can fetch and decode records without
+/// waiting for each other.  Readers are opened on first use.
+///
+pub struct BamReaderPool {
+    bam_file: Box<str>,
+    index_file: Box<str>,
+    threads: Option<usize>,
+    reference: Option<Box<str>>,
+    readers: Vec<Mutex<Option<bam::IndexedReader>>>,
+}
+
+#[allow(dead_code)]
+impl BamReaderPool {
+    /// * `bam_file` - alignment file name
+    /// * `index_file` - index file name
+    pub fn new(bam_file: &str, index_file: &str) -> anyhow::Result<Self> {
+        // fail early if the file cannot be opened
+        check_file_exists(bam_file)?;
+        check_file_exists(index_file)?;
+        let reader = bam::IndexedReader::from_path_and_index(bam_file, index_file)?;
+
+        // one for each worker, and one more for outside callers
+        let nreaders = rayon::current_num_threads() + 1;
+        let mut readers: Vec<_> = (1..nreaders).map(|_| Mutex::new(None)).collect();
+        readers.push(Mutex::new(Some(reader)));
+
+        Ok(BamReaderPool {
+            bam_file: bam_file.into(),
+            index_file: index_file.into(),
+            threads: None,
+            reference: None,
+            readers,
+        })
+    }
+
+    /// Use extra htslib threads to decompress BGZF blocks in each
+    /// reader
+    pub fn set_threads(&mut self, threads: usize) -> anyhow::Result<()> {
+        for reader in self.readers.iter_mut() {
+            let reader = reader
+                .get_mut()
+                .map_err(|_| FabaError::PoisonedLock(self.bam_file.clone()))?;
+            if let Some(reader) = reader.as_mut() {
+                reader.set_threads(threads)?;
+            }
+        }
+        self.threads = Some(threads);
+        Ok(())
+    }
+
+    /// Decode CRAM records with this reference FASTA file (no effect
+    /// on BAM files)
+    pub fn set_reference(&mut self, fasta_file: &str) -> anyhow::Result<()> {
+        if !is_cram(&self.bam_file) {
+            return Ok(());
+        }
+        for reader in self.readers.iter_mut() {
+            let reader = reader
+                .get_mut()
+                .map_err(|_| FabaError::PoisonedLock(self.bam_file.clone()))?;
+            if let Some(reader) = reader.as_mut() {
+                reader.set_reference(fasta_file)?;
+            }
+        }
+        self.reference = Some(fasta_file.into());
+        Ok(())
+    }
+
+    pub fn bam_file(&self) -> &str {
+        &self.bam_file
+    }
+
+    /// Run `f` with the reader of the current rayon worker
+    pub fn with_reader<T, F>(&self, f: F) -> anyhow::Result<T>
+    where
+        F: FnOnce(&mut bam::IndexedReader) -> anyhow::Result<T>,
+    {
+        let at = rayon::current_thread_index()
+            .filter(|&i| i + 1 < self.readers.len())
+            .unwrap_or(self.readers.len() - 1);
+
+        let mut reader = self.readers[at]
+            .lock()
+            .map_err(|_| FabaError::PoisonedLock(self.bam_file.clone()))?;
+
+        if reader.is_none() {
+            let mut new_reader =
+                bam::IndexedReader::from_path_and_index(&*self.bam_file, &*self.index_file)?;
+            if let Some(threads) = self.threads {
+                new_reader.set_threads(threads)?;
+            }
+            if let Some(fasta_file) = self.reference.as_deref() {
+                new_reader.set_reference(fasta_file)?;
+            }
+            *reader = Some(new_reader);
+        }
+
+        match reader.as_mut() {
+            Some(reader) => f(reader),
+            None => Err(anyhow::anyhow!("failed to open {}", self.bam_file)),
+        }
+    }
+}
+
+/// Contig names and lengths in the BAM header
+///
+pub fn read_contigs(bam_file: &str) -> anyhow::Result<Vec<(Box<str>, i64)>> {
+    check_file_exists(bam_file)?;
+    let br = bam::Reader::from_path(bam_file)
+        .map_err(|e| anyhow::anyhow!("failed to initialize BAM file {}: {}", bam_file, e))?;
+
+    let hdr = br.header();
+
+    hdr.target_names()
+        .iter()
+        .enumerate()
+        .map(|(tid, name)| {
+            let max_size = hdr.target_len(tid as u32).unwrap_or(0) as i64;
+            let name = std::str::from_utf8(name)
+                .map_err(|_| FabaError::NonUtf8Contig(String::from_utf8_lossy(name).into()))?;
+            Ok((name.into(), max_size))
+        })
+        .collect()
+}
+
+/// BAI indexes cannot address positions beyond 2^29 - 1
+pub const BAI_MAX_LEN: i64 = (1 << 29) - 1;
+/// Bin size of CSI indexes as in `samtools index -c`
+const CSI_MIN_SHIFT: u32 = 14;
+
+/// Types of random access indexes
+///
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum IndexType {
+    /// Use any existing index, or build BAI unless a target is too
+    /// long for BAI, in which case build CSI
+    #[default]
+    Auto,
+    /// BAI (`.bai`) for targets up to 2^29 - 1 bp
+    Bai,
+    /// CSI (`.csi`) for targets of any length
+    Csi,
+}
+
+/// What to do with stale or mismatched indexes
+///
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum StaleIndex {
+    /// Build a new index
+    #[default]
+    Rebuild,
+    /// Stop with an error
+    Error,
+}
+
+/// Options to find or build indexes of alignment files
+///
+#[derive(Args, Debug, Clone, Default)]
+pub struct IndexOptions {
+    /// Type of the index to use or build
+    #[arg(long, value_enum, default_value = "auto")]
+    pub index_type: IndexType,
+    /// Check that the index points to the reads it counts on a sample
+    /// of targets, besides being newer than the alignment file
+    #[arg(long, default_value_t = false)]
+    pub check_index: bool,
+    /// What to do with indexes older than the alignment file or not
+    /// matching its header
+    #[arg(long, value_enum, default_value = "rebuild")]
+    pub stale_index: StaleIndex,
+    /// Read-only mode: never write next to the alignment files, but
+    /// look for and build indexes in this scratch directory
+    #[arg(long)]
+    pub index_dir: Option<Box<str>>,
+}
+
+/// Index files that may exist next to an alignment file, e.g.,
+/// `x.bam.csi`, `x.csi`, `x.bam.bai`, `x.bai`, and `x.cram.crai`, or
+/// in the scratch directory
+fn index_candidates(bam_file_name: &str, index_dir: Option<&str>) -> Vec<String> {
+    let stem = Path::new(bam_file_name).with_extension("");
+    let stem = stem.to_string_lossy();
+    let mut ret = vec![];
+    for ext in ["csi", "bai", "crai"] {
+        ret.push(format!("{}.{}", bam_file_name, ext));
+        if stem != bam_file_name {
+            ret.push(format!("{}.{}", stem, ext));
+        }
+    }
+    if let Some(dir) = index_dir {
+        for ext in ["csi", "bai", "crai"] {
+            ret.push(scratch_index_file(bam_file_name, dir, ext));
+        }
+    }
+    ret
+}
+
+/// `{dir}/{bam file name}.{ext}`
+fn scratch_index_file(bam_file_name: &str, dir: &str, ext: &str) -> String {
+    let name = Path::new(bam_file_name)
+        .file_name()
+        .map(|x| x.to_string_lossy().to_string())
+        .unwrap_or_else(|| bam_file_name.to_string());
+    Path::new(dir)
+        .join(format!("{}.{}", name, ext))
+        .to_string_lossy()
+        .to_string()
+}
+
+/// Targets to sample when checking an index against its alignment
+/// file, among those with and without reads each
+const MAX_CHECKED_TARGETS: usize = 16;
+
+/// Is the index up to date and, if `check_targets`, consistent with
+/// the alignment file?  The per-target read counts in a BAM index
+/// (as in `samtools idxstats`) tell which targets should have reads.
+/// For a bounded sample of targets, reads are fetched from where the
+/// index points: the first read should be on the target if it has
+/// reads, and none otherwise, and the reads fetched from the middle
+/// of the target should overlap it.  An index of another file with
+/// the same header would point to the wrong offsets.  CRAM indexes
+/// are only checked to fetch, since decoding needs the reference.
+///
+/// * `bam_file_name` - alignment file name
+/// * `idx_file_name` - index file name
+/// * `check_targets` - also check a sample of targets
+///
+pub fn validate_bam_index(
+    bam_file_name: &str,
+    idx_file_name: &str,
+    check_targets: bool,
+) -> anyhow::Result<()> {
+    let bam_time = std::fs::metadata(bam_file_name)?.modified().ok();
+    let idx_time = std::fs::metadata(idx_file_name)?.modified().ok();
+
+    if let (Some(bam_time), Some(idx_time)) = (bam_time, idx_time) {
+        if idx_time < bam_time {
+            return Err(anyhow::anyhow!(
+                "index {} is older than {}",
+                idx_file_name,
+                bam_file_name
+            ));
+        }
+    }
+
+    if !check_targets {
+        return Ok(());
+    }
+
+    let mut reader = bam::IndexedReader::from_path_and_index(bam_file_name, idx_file_name)?;
+    let ntargets = reader.header().target_count();
+    let names: Vec<String> = (0..ntargets)
+        .map(|tid| String::from_utf8_lossy(reader.header().tid2name(tid)).to_string())
+        .collect();
+
+    let mismatch = |tid: u32, what: &str| {
+        anyhow::anyhow!(
+            "index {} does not match {} at target {}: {}",
+            idx_file_name,
+            bam_file_name,
+            names[tid as usize],
+            what
+        )
+    };
+
+    if is_cram(bam_file_name) {
+        // the slow idxstats of CRAM would decode the whole file
+        for tid in sample_evenly(&(0..ntargets).collect::<Vec<_>>(), MAX_CHECKED_TARGETS) {
+            if reader.fetch(tid).is_err() {
+                return Err(mismatch(tid, "failed to fetch"));
+            }
+        }
+        return Ok(());
+    }
+
+    let (with_reads, without_reads): (Vec<_>, Vec<_>) = reader
+        .index_stats()?
+        .into_iter()
+        .filter(|&(tid, _, _, _)| tid >= 0)
+        .partition(|&(_, _, mapped, _)| mapped > 0);
+
+    let with_reads: Vec<u32> = with_reads.into_iter().map(|x| x.0 as u32).collect();
+    let without_reads: Vec<u32> = without_reads.into_iter().map(|x| x.0 as u32).collect();
+
+    let mut rec = bam::Record::new();
+
+    for tid in sample_evenly(&with_reads, MAX_CHECKED_TARGETS) {
+        let len = reader.header().target_len(tid).unwrap_or(0) as i64;
+
+        if reader.fetch(tid).is_err() {
+            return Err(mismatch(tid, "failed to fetch"));
+        }
+        match reader.read(&mut rec) {
+            None => return Err(mismatch(tid, "no reads where the index has some")),
+            Some(Err(e)) => return Err(mismatch(tid, &e.to_string())),
+            Some(Ok(())) if rec.tid() != tid as i32 || rec.pos() >= len => {
+                return Err(mismatch(tid, "reads out of the target"));
+            }
+            Some(Ok(())) => {}
+        }
+
+        let mid = len / 2;
+        if reader.fetch((tid, mid, len)).is_err() {
+            return Err(mismatch(tid, "failed to fetch"));
+        }
+        match reader.read(&mut rec) {
+            None => {}
+            Some(Err(e)) => return Err(mismatch(tid, &e.to_string())),
+            Some(Ok(())) if rec.tid() != tid as i32 || rec.cigar().end_pos() <= mid => {
+                return Err(mismatch(tid, "reads out of the region"));
+            }
+            Some(Ok(())) => {}
+        }
+    }
+
+    for tid in sample_evenly(&without_reads, MAX_CHECKED_TARGETS) {
+        if reader.fetch(tid).is_err() {
+            return Err(mismatch(tid, "failed to fetch"));
+        }
+        match reader.read(&mut rec) {
+            None => {}
+            Some(Err(e)) => return Err(mismatch(tid, &e.to_string())),
+            Some(Ok(())) => return Err(mismatch(tid, "reads where the index has none")),
+        }
+    }
+
+    Ok(())
+}
+
+/// At most `max` items spread evenly over the list, including the
+/// first and last
+fn sample_evenly(items: &[u32], max: usize) -> Vec<u32> {
+    let n = items.len();
+    if n <= max {
+        return items.to_vec();
+    }
+    (0..max).map(|k| items[k * (n - 1) / (max - 1)]).collect()
+}
+
+/// Check random access BAM index.  Without an index file name, look
+/// for an existing `.csi`, `.bai` or `.crai` (for CRAM) index next to
+/// the BAM file (or in the scratch directory).  Indexes older than the BAM
+/// file, or not matching its targets if checked, are rebuilt or
+/// rejected.  If none fits, build a new one, CSI if requested or if
+/// any target is longer than BAI can address.  In the read-only
+/// mode, new indexes are only written to the scratch directory.
+///
+/// * `bam_file_name` - alignment file name
+/// * `idx_file_name` - index file name (optional)
+/// * `options` - which type of index to use or build, and where
 ///
 #[allow(dead_code)]
 pub fn check_bam_index(
     bam_file_name: &str,
     idx_file_name: Option<&str>,
+    options: &IndexOptions,
 ) -> anyhow::Result<Box<str>> {
     // log::info!("Checking BAM index");
 
-    let idx_file = match idx_file_name {
-        Some(x) => String::from(x),
-        None => format!("{}.bai", bam_file_name),
+    let max_len = read_contigs(bam_file_name)?
+        .iter()
+        .map(|x| x.1)
+        .max()
+        .unwrap_or(0);
+    let too_long = max_len > BAI_MAX_LEN;
+    let cram = is_cram(bam_file_name);
+
+    if options.index_type == IndexType::Bai && too_long && !cram {
+        return Err(anyhow::anyhow!(
+            "{} has a target of {} bp, beyond the BAI limit of {} bp; use CSI instead",
+            bam_file_name,
+            max_len,
+            BAI_MAX_LEN
+        ));
+    }
+
+    let fits = |idx_file: &str| match Path::new(idx_file).extension().and_then(|x| x.to_str()) {
+        Some("bai") => options.index_type != IndexType::Csi && !too_long && !cram,
+        Some("csi") => options.index_type != IndexType::Bai && !cram,
+        _ => cram,
+    };
+
+    let use_csi = match idx_file_name {
+        Some(x) if x.ends_with(".csi") => true,
+        Some(x) if x.ends_with(".bai") => false,
+        _ => options.index_type == IndexType::Csi || too_long,
     };
 
-    if Path::new(&idx_file).exists() {
-        return Ok(idx_file.into_boxed_str());
+    let candidates = match idx_file_name {
+        Some(x) => vec![x.to_string()],
+        None => index_candidates(bam_file_name, options.index_dir.as_deref())
+            .into_iter()
+            .filter(|x| fits(x))
+            .collect(),
+    };
+
+    for idx_file in candidates.into_iter().filter(|x| Path::new(x).exists()) {
+        match validate_bam_index(bam_file_name, &idx_file, options.check_index) {
+            Ok(()) => return Ok(idx_file.into_boxed_str()),
+            Err(e) if options.stale_index == StaleIndex::Error => return Err(e),
+            Err(e) => println!("Rebuilding the index: {}", e),
+        }
     }
 
-    let ncore = thread::available_parallelism()
-        .expect("failed to figure out number of cores")
-        .get();
+    // htslib always builds `.crai` indexes for CRAM
+    let ext = match (cram, use_csi) {
+        (true, _) => "crai",
+        (_, true) => "csi",
+        _ => "bai",
+    };
+
+    let idx_file = match (options.index_dir.as_deref(), idx_file_name) {
+        (Some(dir), _) => {
+            std::fs::create_dir_all(dir)?;
+            scratch_index_file(bam_file_name, dir, ext)
+        }
+        (None, Some(x)) => String::from(x),
+        (None, None) => format!("{}.{}", bam_file_name, ext),
+    };
+
+    let ncore = thread::available_parallelism().map_or(1, |x| x.get());
 
     // log::info!(
     //     "Creating a new index file {} using {} cores",
@@ -52,13 +708,41 @@ pub fn check_bam_index(
     //     &ncore
     // );
 
+    let idx_type = match use_csi {
+        true => bam::index::Type::Csi(CSI_MIN_SHIFT),
+        _ => bam::index::Type::Bai,
+    };
+
     // need to build an index for this bam file
-    bam::index::build(
-        bam_file_name,
-        Some(&idx_file),
-        bam::index::Type::Bai,
-        ncore as u32,
-    )?;
+    bam::index::build(bam_file_name, Some(&idx_file), idx_type, ncore as u32)?;
 
     Ok(idx_file.into_boxed_str())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn library_type_flips() {
+        // (library type, flips of single-end reads, read 1, read 2)
+        let expected = [
+            (LibraryType::Unstranded, [false, false, false]),
+            (LibraryType::FrFirststrand, [true, true, false]),
+            (LibraryType::FrSecondstrand, [false, false, true]),
+            (LibraryType::TenX3p, [false, false, true]),
+            (LibraryType::TenX5p, [true, false, true]),
+        ];
+        for (library_type, flips) in expected {
+            for (mate, flip) in flips.into_iter().enumerate() {
+                assert_eq!(
+                    library_type.flips(mate),
+                    flip,
+                    "{} mate {}",
+                    library_type,
+                    mate
+                );
+            }
+        }
+    }
+}
@@ -1,11 +1,11 @@
 use crate::util::bam::*;
+use crate::util::error::FabaError;
 
 use rust_htslib::bam::{self, ext::BamRecordExtensions, record::Aux, Read};
 use std::cmp::max;
 use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
 pub enum Dna {
     A,
     T,
@@ -13,15 +13,82 @@ pub enum Dna {
     C,
 }
 
+#[allow(dead_code)]
+impl Dna {
+    /// all the bases in the order of `index()`
+    pub const ALL: [Dna; 4] = [Dna::A, Dna::T, Dna::G, Dna::C];
+
+    pub fn from_byte(b: u8) -> Option<Self> {
+        match b {
+            b'A' | b'a' => Some(Dna::A),
+            b'T' | b't' => Some(Dna::T),
+            b'G' | b'g' => Some(Dna::G),
+            b'C' | b'c' => Some(Dna::C),
+            _ => None,
+        }
+    }
+
+    pub fn index(&self) -> usize {
+        match self {
+            Dna::A => 0,
+            Dna::T => 1,
+            Dna::G => 2,
+            Dna::C => 3,
+        }
+    }
+
+    pub fn complement(&self) -> Self {
+        match self {
+            Dna::A => Dna::T,
+            Dna::T => Dna::A,
+            Dna::G => Dna::C,
+            Dna::C => Dna::G,
+        }
+    }
+}
+
+/// Display DNA bases
+///
+impl std::fmt::Display for Dna {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Dna::A => write!(f, "A"),
+            Dna::T => write!(f, "T"),
+            Dna::G => write!(f, "G"),
+            Dna::C => write!(f, "C"),
+        }
+    }
+}
+
+/// Which strand the reads were aligned to
+///
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+pub enum DnaStrand {
+    Forward,
+    Reverse,
+}
+
+/// Display strands as in GFF/BED
+///
+impl std::fmt::Display for DnaStrand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DnaStrand::Forward => write!(f, "+"),
+            DnaStrand::Reverse => write!(f, "-"),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct DnaBaseStat {
     data: [(Dna, f32); 4],
     gpos: i64,
+    reference: Option<Dna>,
 }
 
 #[allow(dead_code)]
 impl DnaBaseStat {
-    fn new(gpos: i64) -> Self {
+    pub fn new(gpos: i64) -> Self {
         DnaBaseStat {
             data: [
                 (Dna::A, 0f32),
@@ -30,6 +97,7 @@ impl DnaBaseStat {
                 (Dna::C, 0f32),
             ],
             gpos,
+            reference: None,
         }
     }
 
@@ -37,6 +105,15 @@ impl DnaBaseStat {
         self.gpos
     }
 
+    /// reference base on the forward strand of the genome
+    pub fn reference(&self) -> Option<Dna> {
+        self.reference
+    }
+
+    pub fn set_reference(&mut self, reference: Option<Dna>) {
+        self.reference = reference;
+    }
+
     pub fn set(&mut self, b: Dna, val: f32) {
         match b {
             Dna::A => self.data[0].1 = val,
@@ -64,11 +141,24 @@ impl DnaBaseStat {
         }
     }
 
+    /// total number of reads mapped on this position
+    pub fn total(&self) -> f32 {
+        self.data.iter().map(|x| x.1).sum()
+    }
+
+    /// accumulate counts of another stat at the same position
+    pub fn add_stat(&mut self, other: &DnaBaseStat) {
+        debug_assert_eq!(self.gpos, other.gpos);
+        for (x, y) in self.data.iter_mut().zip(other.data.iter()) {
+            x.1 += y.1;
+        }
+        if self.reference.is_none() {
+            self.reference = other.reference;
+        }
+    }
+
     pub fn most_frequent(&self) -> &(Dna, f32) {
-        self.data
-            .iter()
-            .max_by(|x, y| x.1.partial_cmp(&y.1).unwrap())
-            .unwrap()
+        self.data.iter().max_by(|x, y| x.1.total_cmp(&y.1)).unwrap()
     }
 
     pub fn second_most_frequent(&self) -> &(Dna, f32) {
@@ -76,7 +166,7 @@ impl DnaBaseStat {
         self.data
             .iter()
             .filter(|s| s.0 != mfa.0)
-            .max_by(|x, y| x.1.partial_cmp(&y.1).unwrap())
+            .max_by(|x, y| x.1.total_cmp(&y.1))
             .unwrap()
     }
 
@@ -86,16 +176,37 @@ impl DnaBaseStat {
             .data
             .iter()
             .filter(|s| s.0 != fst.0)
-            .max_by(|x, y| x.1.partial_cmp(&y.1).unwrap())
+            .max_by(|x, y| x.1.total_cmp(&y.1))
             .unwrap();
 
         BiAllele {
-            a1: fst.0.clone(),
-            a2: snd.0.clone(),
+            a1: fst.0,
+            a2: snd.0,
             n1: fst.1,
             n2: snd.1,
         }
     }
+
+    /// Reference and the most frequent alternative allele.  Without
+    /// the reference base, the major allele stands in for it.
+    pub fn ref_alt_stat(&self) -> BiAllele {
+        let Some(reference) = self.reference else {
+            return self.bi_allelic_stat();
+        };
+        let alt = self
+            .data
+            .iter()
+            .filter(|s| s.0 != reference)
+            .max_by(|x, y| x.1.total_cmp(&y.1))
+            .unwrap();
+
+        BiAllele {
+            a1: reference,
+            a2: alt.0,
+            n1: self.get(reference),
+            n2: alt.1,
+        }
+    }
 }
 
 #[allow(dead_code)]
@@ -106,65 +217,105 @@ pub struct BiAllele {
     pub n2: f32,
 }
 
-/// Extract DNA base pair frequency tables in multi-threaded visits
-/// over BAM file reader. Here, we only go through aligned reads.
+/// Extract DNA base pair frequency tables over BAM file reader.
+/// Here, we only go through aligned reads.  Reads are assigned to
+/// the forward or reverse strand by the transcript strand of the
+/// library type.  Parallel callers should take their own readers
+/// from [`BamReaderPool`].  Fails with [`FabaError::EmptyRegion`] if
+/// no reads pass the filters, or [`FabaError::UnknownContig`].
 ///
-/// * `arc_bam` - Arc, Mutex of indexed BAM reader
+/// * `bam_reader` - indexed BAM reader
 /// * `region` - a genomic region triplet
-/// 
+/// * `read_filters` - filters on reads and bases
+///
 pub fn get_dna_base_freq(
-    arc_bam: &Arc<Mutex<&mut bam::IndexedReader>>,
+    bam_reader: &mut bam::IndexedReader,
     region: (&str, i64, i64),
+    read_filters: &ReadFilters,
 ) -> anyhow::Result<DnaStatMap> {
-    let (_, lb, ub) = region;
+    count_dna_bases(bam_reader, region, None, read_filters)
+}
 
-    let mut bam_reader = arc_bam.lock().expect("unable to lock the reader");
+/// Extract DNA base pair frequency tables only at the given
+/// positions within the region, fetching the reads just once.
+///
+/// * `bam_reader` - indexed BAM reader
+/// * `region` - a genomic region triplet
+/// * `positions` - sorted genomic positions within the region
+/// * `read_filters` - filters on reads and bases
+///
+pub fn get_dna_base_freq_at(
+    bam_reader: &mut bam::IndexedReader,
+    region: (&str, i64, i64),
+    positions: &[i64],
+    read_filters: &ReadFilters,
+) -> anyhow::Result<DnaStatMap> {
+    debug_assert!(positions.windows(2).all(|w| w[0] <= w[1]));
+    count_dna_bases(bam_reader, region, Some(positions), read_filters)
+}
 
-    bam_reader
-        .fetch(region)
-        .expect("unable to fetch the region");
+fn count_dna_bases(
+    bam_reader: &mut bam::IndexedReader,
+    region: (&str, i64, i64),
+    positions: Option<&[i64]>,
+    read_filters: &ReadFilters,
+) -> anyhow::Result<DnaStatMap> {
+    let (chr, lb, ub) = region;
 
     if lb >= ub {
         return Err(anyhow::anyhow!("lb >= ub"));
     }
 
-    let bam_records: Vec<bam::Record> = bam_reader
-        .records()
-        .into_iter()
-        .filter_map(Result::ok)
-        .filter(|rec| !rec.is_duplicate())
-        .collect();
+    if bam_reader.header().tid(chr.as_bytes()).is_none() {
+        return Err(FabaError::UnknownContig(chr.into()).into());
+    }
+
+    bam_reader
+        .fetch(region)
+        .map_err(|e| anyhow::anyhow!("unable to fetch {}:{}-{}: {}", chr, lb, ub, e))?;
+
+    let mut bam_records: Vec<bam::Record> = vec![];
+    for rec in bam_reader.records() {
+        let rec = rec?;
+        if read_filters.pass_read(&rec) {
+            bam_records.push(rec);
+        }
+    }
 
-    if bam_records.len() < 1 {
-        return Err(anyhow::anyhow!("Empty region"));
+    if bam_records.is_empty() {
+        return Err(FabaError::EmptyRegion.into());
     }
 
-    // map: sample -> forward/reverse frequency vectors
-    let mut ret = DnaStatMap::new();
-    ret.new_sample(&BamSample::Combined, lb, ub);
+    // map: sample -> forward/reverse sparse frequency vectors
+    let mut ret = DnaStatMap::new(lb, ub);
+
+    // map: (sample, UMI, is_reverse, position) -> base votes, where
+    // `is_reverse` refers to the transcript strand of the read
+    let mut umi_votes: HashMap<(BamSample, Box<str>, bool, usize), [f32; 4]> = HashMap::new();
+    ret.new_sample(&BamSample::Combined);
     // dbg!("added combined");
 
     for rec in bam_records {
-        let mut sample_id = BamSample::Combined;
-
-        // https://docs.rs/rust-htslib/0.47.0/rust_htslib/bam/record/enum.Aux.html
         // extract 10x cell barcode
-        if let Ok(aux) = rec.aux(b"CB") {
-            if let Aux::String(cb) = aux {
-                sample_id = BamSample::Barcode(cb.into());
-                if !ret.has_sample(&sample_id) {
-                    // dbg!("added new cell barcode");
-                    ret.new_sample(&sample_id, lb, ub);
-                }
-            }
+        let Some(sample_id) = read_filters.sample_of(&rec) else {
+            continue;
+        };
+        if !ret.has_sample(&sample_id) {
+            ret.new_sample(&sample_id);
         }
 
         // extract 10x UMI barcode
-        // if let Ok(umi) = rec.aux(b"UB") {
-        //     dbg!(umi);
-        // }
+        let umi: Option<Box<str>> = match read_filters.umi_tag.as_bytes() {
+            [] => None,
+            tag => match rec.aux(tag) {
+                Ok(Aux::String(ub)) => Some(ub.into()),
+                _ => None,
+            },
+        };
 
         let seq = rec.seq().as_bytes();
+        let (rlb, rub) = read_filters.base_range(&rec);
+        let is_reverse = read_filters.library_type.is_reverse(&rec);
 
         //
         // Iter aligned read and reference positions on a basepair level
@@ -178,50 +329,99 @@ pub fn get_dna_base_freq(
                 continue;
             }
 
-            let bp = seq[r];
+            if positions.is_some_and(|pp| pp.binary_search(&gpos).is_err()) {
+                continue;
+            }
+
+            if r < rlb || r >= rub || !read_filters.pass_base(&rec, r) {
+                continue;
+            }
+
+            let Some(b) = Dna::from_byte(seq[r]) else {
+                continue;
+            };
+
+            // reads of the same molecule will vote later
+            if let Some(umi) = umi.as_ref() {
+                let key = (sample_id.clone(), umi.clone(), is_reverse, v as usize);
+                umi_votes.entry(key).or_insert([0_f32; 4])[b.index()] += 1_f32;
+                continue;
+            }
 
-            let freq = match rec.is_reverse() {
+            let freq = match is_reverse {
                 true => ret.get_reverse_base_mut(&sample_id, v as usize),
                 _ => ret.get_forward_base_mut(&sample_id, v as usize),
             };
 
             if let Some(freq) = freq {
                 debug_assert_eq!(freq.gpos, gpos);
-                match bp {
-                    b'A' | b'a' => freq.add(Dna::A, 1.),
-                    b'T' | b't' => freq.add(Dna::T, 1.),
-                    b'G' | b'g' => freq.add(Dna::G, 1.),
-                    b'C' | b'c' => freq.add(Dna::C, 1.),
-                    _ => (),
-                }
+                freq.add(b, 1.);
             }
         }
     }
 
+    // count each UMI family once by majority vote, visiting positions
+    // in order so that new stats are appended to the sorted vectors
+    let mut umi_votes: Vec<_> = umi_votes.into_iter().collect();
+    umi_votes.sort_unstable_by_key(|((_, _, _, v), _)| *v);
+
+    for ((sample_id, _, is_reverse, v), votes) in umi_votes {
+        let Some(b) = majority_vote(&votes) else {
+            continue;
+        };
+
+        let freq = match is_reverse {
+            true => ret.get_reverse_base_mut(&sample_id, v),
+            _ => ret.get_forward_base_mut(&sample_id, v),
+        };
+
+        if let Some(freq) = freq {
+            freq.add(b, 1.);
+        }
+    }
+
     Ok(ret)
 }
 
+/// The base supported by most reads, or `None` if tied
+fn majority_vote(votes: &[f32; 4]) -> Option<Dna> {
+    let max = votes.iter().cloned().fold(0_f32, f32::max);
+    let mut winners = Dna::ALL.into_iter().filter(|b| votes[b.index()] == max);
+    match (max > 0_f32, winners.next(), winners.next()) {
+        (true, Some(b), None) => Some(b),
+        _ => None,
+    }
+}
+
 /// DNA frequency map from forward and reverse strands
+///
+/// Each sample only keeps the positions covered by its reads in a
+/// list sorted by genomic position, so that thousands of cell
+/// barcodes in a block don't have to allocate dense vectors.
 #[allow(dead_code)]
 pub struct DnaStatMap {
     forward: HashMap<usize, Vec<DnaBaseStat>>,
     reverse: HashMap<usize, Vec<DnaBaseStat>>,
     samp2id: HashMap<BamSample, usize>,
     id2samp: Vec<BamSample>,
+    lb: i64,
+    ub: i64,
 }
 
 #[allow(dead_code)]
 impl DnaStatMap {
     /// Create Dna Stat Map containing both forward and reverse
-    /// directions.
+    /// directions over the genomic positions `[lb, ub)`.
     ///
-    /// map: sample_id -> dna stat vector
-    fn new() -> Self {
+    /// map: sample_id -> sorted dna stat vector
+    fn new(lb: i64, ub: i64) -> Self {
         DnaStatMap {
             forward: HashMap::new(),
             reverse: HashMap::new(),
             samp2id: HashMap::new(),
             id2samp: vec![],
+            lb,
+            ub: max(ub, lb),
         }
     }
 
@@ -237,8 +437,8 @@ impl DnaStatMap {
     //     self.id2samp.len()
     // }
 
-    pub fn new_sample(&mut self, key: &BamSample, lb: i64, ub: i64) {
-        if !self.has_sample(&key) {
+    pub fn new_sample(&mut self, key: &BamSample) {
+        if !self.has_sample(key) {
             let id = self.id2samp.len();
             self.samp2id.insert(key.clone(), id); //
             self.id2samp.push(key.clone()); // check
@@ -246,47 +446,78 @@ impl DnaStatMap {
             debug_assert_eq!(self.id2samp.len(), id + 1);
             debug_assert_eq!(self.samp2id.len(), id + 1);
 
-            let nn = max(ub - lb, 0i64) as usize;
-
-            let forward_freq = self
-                .forward
-                .entry(id)
-                .or_insert_with(|| Vec::with_capacity(nn));
-
-            let reverse_freq = self
-                .reverse
-                .entry(id)
-                .or_insert_with(|| Vec::with_capacity(nn));
+            self.forward.insert(id, vec![]);
+            self.reverse.insert(id, vec![]);
+        }
+    }
 
-            for g in lb..ub {
-                forward_freq.push(DnaBaseStat::new(g));
-                reverse_freq.push(DnaBaseStat::new(g));
+    /// Set reference bases of all the samples on both strands
+    ///
+    /// * `lb` - genomic position of the first base in `seq`
+    /// * `seq` - reference sequence on the forward strand
+    pub fn set_reference(&mut self, lb: i64, seq: &[u8]) {
+        for stats in self.forward.values_mut().chain(self.reverse.values_mut()) {
+            for bs in stats.iter_mut() {
+                let at = bs.gpos - lb;
+                if at >= 0 {
+                    bs.reference = seq.get(at as usize).and_then(|&b| Dna::from_byte(b));
+                }
             }
-            debug_assert_eq!(forward_freq.len(), nn);
-            debug_assert_eq!(reverse_freq.len(), nn);
         }
     }
 
+    /// Forward-strand statistics of the positions covered by this
+    /// sample, sorted by genomic position
     pub fn get_forward(&self, key: &BamSample) -> Option<&Vec<DnaBaseStat>> {
-        self.samp2id.get(&key).and_then(|id| self.forward.get(id))
+        self.samp2id.get(key).and_then(|id| self.forward.get(id))
     }
 
+    /// Reverse-strand statistics of the positions covered by this
+    /// sample, sorted by genomic position
     pub fn get_reverse(&self, key: &BamSample) -> Option<&Vec<DnaBaseStat>> {
-        self.samp2id.get(&key).and_then(|id| self.reverse.get(id))
+        self.samp2id.get(key).and_then(|id| self.reverse.get(id))
     }
 
+    /// * `at` - offset from the lower bound of the block
     pub fn get_forward_base_mut(&mut self, key: &BamSample, at: usize) -> Option<&mut DnaBaseStat> {
+        let gpos = self.lb + at as i64;
+        if gpos >= self.ub {
+            return None;
+        }
         self.samp2id
-            .get(&key)
+            .get(key)
             .and_then(|id| self.forward.get_mut(id))
-            .and_then(|vv| vv.get_mut(at))
+            .map(|vv| find_or_insert(vv, gpos))
     }
 
+    /// * `at` - offset from the lower bound of the block
     pub fn get_reverse_base_mut(&mut self, key: &BamSample, at: usize) -> Option<&mut DnaBaseStat> {
+        let gpos = self.lb + at as i64;
+        if gpos >= self.ub {
+            return None;
+        }
         self.samp2id
-            .get(&key)
+            .get(key)
             .and_then(|id| self.reverse.get_mut(id))
-            .and_then(|vv| vv.get_mut(at))
+            .map(|vv| find_or_insert(vv, gpos))
+    }
+}
+
+/// Locate the stat of `gpos` in the sorted vector, inserting an empty
+/// one if needed.  Reads come sorted by their start positions, so new
+/// positions are mostly appended at the end.
+fn find_or_insert(stats: &mut Vec<DnaBaseStat>, gpos: i64) -> &mut DnaBaseStat {
+    let at = match stats.last() {
+        Some(bs) if bs.gpos < gpos => Err(stats.len()),
+        None => Err(0),
+        _ => stats.binary_search_by_key(&gpos, |bs| bs.gpos),
+    };
+    match at {
+        Ok(i) => &mut stats[i],
+        Err(i) => {
+            stats.insert(i, DnaBaseStat::new(gpos));
+            &mut stats[i]
+        }
     }
 }
 
@@ -0,0 +1,46 @@
+/// Errors of faba routines that callers may want to tell apart, e.g.,
+/// to skip an empty region but stop at a missing file.  They are
+/// carried in `anyhow::Error` and can be checked by `is_faba_error`.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FabaError {
+    /// the file does not exist
+    MissingFile(Box<str>),
+    /// the contig is not in the header of the alignment file
+    UnknownContig(Box<str>),
+    /// a target name in the header is not valid UTF-8 (shown lossily)
+    NonUtf8Contig(Box<str>),
+    /// a thread panicked while holding the lock on this resource
+    PoisonedLock(Box<str>),
+    /// no reads passed the filters in the region
+    EmptyRegion,
+}
+
+impl std::fmt::Display for FabaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FabaError::MissingFile(x) => write!(f, "no such file: {}", x),
+            FabaError::UnknownContig(x) => write!(f, "unknown contig: {}", x),
+            FabaError::NonUtf8Contig(x) => write!(f, "contig name is not valid UTF-8: {}", x),
+            FabaError::PoisonedLock(x) => write!(f, "poisoned lock on {}", x),
+            FabaError::EmptyRegion => write!(f, "empty region"),
+        }
+    }
+}
+
+impl std::error::Error for FabaError {}
+
+/// Is this error the given faba error?
+#[allow(dead_code)]
+pub fn is_faba_error(error: &anyhow::Error, kind: &FabaError) -> bool {
+    error.downcast_ref::<FabaError>() == Some(kind)
+}
+
+/// Fail early with a clear message if the file does not exist
+#[allow(dead_code)]
+pub fn check_file_exists(file: &str) -> anyhow::Result<()> {
+    if !std::path::Path::new(file).exists() {
+        return Err(FabaError::MissingFile(file.into()).into());
+    }
+    Ok(())
+}
@@ -0,0 +1,85 @@
+use rust_htslib::htslib;
+use std::ffi::CString;
+use std::path::Path;
+
+/// Random access to an indexed FASTA file (plain or bgzipped).  The
+/// `.fai` (and `.gzi`) index will be created if missing.
+///
+/// We call htslib directly since `faidx::Reader` neither checks a
+/// failed load nor frees fetched sequences.
+///
+pub struct FastaReader {
+    inner: *mut htslib::faidx_t,
+}
+
+// htslib's faidx_t can move across threads as long as it is not
+// accessed concurrently, e.g., behind a Mutex
+unsafe impl Send for FastaReader {}
+
+#[allow(dead_code)]
+impl FastaReader {
+    pub fn from_path(fasta_file: &str) -> anyhow::Result<Self> {
+        if !Path::new(fasta_file).exists() {
+            return Err(anyhow::anyhow!("no such FASTA file: {}", fasta_file));
+        }
+        let cpath = CString::new(fasta_file)?;
+        let inner = unsafe { htslib::fai_load(cpath.as_ptr()) };
+        if inner.is_null() {
+            return Err(anyhow::anyhow!(
+                "failed to load/index FASTA: {}",
+                fasta_file
+            ));
+        }
+        Ok(FastaReader { inner })
+    }
+
+    /// Does this reference have the sequence?
+    pub fn has_seq(&self, chr: &str) -> bool {
+        match CString::new(chr) {
+            Ok(cname) => unsafe { htslib::faidx_has_seq(self.inner, cname.as_ptr()) != 0 },
+            _ => false,
+        }
+    }
+
+    /// Length of the sequence, or `None` if missing
+    pub fn seq_len(&self, chr: &str) -> Option<i64> {
+        if !self.has_seq(chr) {
+            return None;
+        }
+        let cname = CString::new(chr).ok()?;
+        let len = unsafe { htslib::faidx_seq_len64(self.inner, cname.as_ptr()) };
+        (len >= 0).then_some(len)
+    }
+
+    /// Fetch upper-case bases in a 0-based, half-open region [lb, ub)
+    pub fn fetch(&self, chr: &str, lb: i64, ub: i64) -> anyhow::Result<Vec<u8>> {
+        if lb >= ub {
+            return Err(anyhow::anyhow!("lb >= ub"));
+        }
+        if !self.has_seq(chr) {
+            return Err(anyhow::anyhow!("{} not found in the reference", chr));
+        }
+        let cname = CString::new(chr)?;
+        let mut len: htslib::hts_pos_t = 0;
+        let ptr =
+            unsafe { htslib::faidx_fetch_seq64(self.inner, cname.as_ptr(), lb, ub - 1, &mut len) };
+        if ptr.is_null() || len < 0 {
+            return Err(anyhow::anyhow!("failed to fetch {}:{}-{}", chr, lb, ub));
+        }
+        let seq = unsafe {
+            let bytes = std::slice::from_raw_parts(ptr as *const u8, len as usize);
+            let seq = bytes.to_ascii_uppercase();
+            htslib::hts_free(ptr as *mut std::os::raw::c_void);
+            seq
+        };
+        Ok(seq)
+    }
+}
+
+impl Drop for FastaReader {
+    fn drop(&mut self) {
+        unsafe {
+            htslib::fai_destroy(self.inner);
+        }
+    }
+}
@@ -2,7 +2,7 @@ use anyhow;
 
 use rust_htslib::bgzf;
 use std::fs::File;
-use std::io::{BufRead, BufReader}; // TODO: BufWriter, Stdout, Write
+use std::io::{BufRead, BufReader, BufWriter, Write};
 use std::path::Path;
 
 ///
@@ -29,3 +29,29 @@ pub fn read_lines(input_file: &str) -> anyhow::Result<Vec<Box<str>>> {
     }
     Ok(lines)
 }
+
+///
+/// Write every line to the output_file, compressed if the file name
+/// ends with `.gz` or `.bgz`
+///
+#[allow(dead_code)]
+pub fn write_lines(lines: &[Box<str>], output_file: &str) -> anyhow::Result<()> {
+    let mut buf: Box<dyn Write> = match Path::new(output_file).extension().and_then(|x| x.to_str())
+    {
+        Some("gz") | Some("bgz") => {
+            let _file = bgzf::Writer::from_path(output_file)?;
+            Box::new(BufWriter::new(_file))
+        }
+
+        _ => {
+            let _file = File::create(output_file)?;
+            Box::new(BufWriter::new(_file))
+        }
+    };
+
+    for x in lines {
+        writeln!(buf, "{}", x)?;
+    }
+    buf.flush()?;
+    Ok(())
+}
@@ -1,4 +1,8 @@
+use crate::util::dna::DnaStrand;
+use crate::util::file::read_lines;
+
 use bio::io::gff;
+use std::collections::HashMap;
 
 /// Parse a GFF line to a record
 ///
@@ -6,7 +10,7 @@ use bio::io::gff;
 ///
 pub fn parse(line: Box<str>) -> Option<Box<gff::Record>> {
     const SEP: char = '\t';
-    const SEP_ATTR: char = ':';
+    const SEP_ATTR: [char; 3] = ['=', ' ', ':'];
     const NUM_FIELDS: usize = 9;
 
     // we can access immutatble &str
@@ -28,11 +32,11 @@ pub fn parse(line: Box<str>) -> Option<Box<gff::Record>> {
             _ => gff::Phase::from(words[7].parse().unwrap_or(0u8)),
         };
 
-        for z in words[8].split_whitespace() {
-            let kv: Vec<&str> = z.split(SEP_ATTR).collect();
-            if kv.len() == 2 {
+        // GFF3 `key=value;` or GTF `key "value";`
+        for z in words[8].split(';') {
+            if let Some((k, v)) = z.trim().split_once(SEP_ATTR) {
                 rec.attributes_mut()
-                    .insert(kv[0].to_string(), kv[1].to_string());
+                    .insert(k.trim().to_string(), v.trim().trim_matches('"').to_string());
             }
         }
         Some(Box::new(rec))
@@ -40,3 +44,102 @@ pub fn parse(line: Box<str>) -> Option<Box<gff::Record>> {
         None
     }
 }
+
+/// A named feature made of one or more GFF records on the same
+/// chromosome and strand, e.g., all the exons of a gene
+///
+pub struct GffFeature {
+    pub name: Box<str>,
+    pub chr: Box<str>,
+    pub strand: Option<DnaStrand>,
+    /// 0-based, half-open intervals sorted by start
+    pub intervals: Vec<(i64, i64)>,
+}
+
+#[allow(dead_code)]
+impl GffFeature {
+    pub fn start(&self) -> i64 {
+        self.intervals.iter().map(|x| x.0).min().unwrap_or(0)
+    }
+
+    pub fn end(&self) -> i64 {
+        self.intervals.iter().map(|x| x.1).max().unwrap_or(0)
+    }
+
+    /// Does this feature overlap with [lb, ub)?
+    pub fn overlaps(&self, lb: i64, ub: i64) -> bool {
+        self.intervals.iter().any(|&(s, e)| s < ub && lb < e)
+    }
+}
+
+type FeatureKey = (Box<str>, Box<str>, Option<DnaStrand>);
+
+/// Read features of the given types from a GFF file and group the
+/// records sharing the same name on the same chromosome and strand.
+///
+/// * `gff_file` - GFF/GTF file name (can be gzipped)
+/// * `feature_types` - feature types to keep, e.g., `gene`, `exon`
+/// * `name_key` - attribute to name features, falling back to `ID`,
+///   `Name`, and `chr:start-end`
+///
+#[allow(dead_code)]
+pub fn read_gff_features(
+    gff_file: &str,
+    feature_types: &[Box<str>],
+    name_key: &str,
+) -> anyhow::Result<Vec<GffFeature>> {
+    let mut features: HashMap<FeatureKey, Vec<(i64, i64)>> = HashMap::new();
+
+    for line in read_lines(gff_file)? {
+        if line.starts_with('#') {
+            continue;
+        }
+        let Some(rec) = parse(line) else {
+            continue;
+        };
+        if !feature_types
+            .iter()
+            .any(|x| x.as_ref() == rec.feature_type())
+        {
+            continue;
+        }
+
+        let chr: Box<str> = rec.seqname().into();
+        let (lb, ub) = (*rec.start() as i64 - 1, *rec.end() as i64);
+
+        let name: Box<str> = match [name_key, "ID", "Name"]
+            .iter()
+            .find_map(|k| rec.attributes().get(*k))
+        {
+            Some(x) => x.as_str().into(),
+            _ => format!("{}:{}-{}", chr, lb + 1, ub).into_boxed_str(),
+        };
+
+        let strand = match rec.strand().as_ref().map(|x| x.strand_symbol()) {
+            Some("+") => Some(DnaStrand::Forward),
+            Some("-") => Some(DnaStrand::Reverse),
+            _ => None,
+        };
+
+        features
+            .entry((name, chr, strand))
+            .or_default()
+            .push((lb.max(0), ub));
+    }
+
+    let mut ret: Vec<GffFeature> = features
+        .into_iter()
+        .map(|((name, chr, strand), mut intervals)| {
+            intervals.sort();
+            GffFeature {
+                name,
+                chr,
+                strand,
+                intervals,
+            }
+        })
+        .collect();
+
+    ret.sort_by(|a, b| (&a.chr, a.start(), &a.name).cmp(&(&b.chr, b.start(), &b.name)));
+    Ok(ret)
+}
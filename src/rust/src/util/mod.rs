@@ -3,3 +3,8 @@ pub mod dna;
 pub mod misc;
 pub mod gff;
 pub mod file;
+pub mod mtx;
+pub mod fasta;
+pub mod strandedness;
+pub mod regions;
+pub mod error;
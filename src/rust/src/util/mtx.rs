@@ -0,0 +1,78 @@
+use crate::util::file::write_lines;
+
+/// Write a sparse matrix in the Matrix Market coordinate format
+///
+/// * `triplets` - 0-based (row, column, value) triplets
+/// * `nrow` - number of rows
+/// * `ncol` - number of columns
+/// * `output_file` - file name (compressed if it ends with `.gz`)
+///
+#[allow(dead_code)]
+pub fn write_mtx(
+    triplets: &[(usize, usize, f32)],
+    nrow: usize,
+    ncol: usize,
+    output_file: &str,
+) -> anyhow::Result<()> {
+    let mut lines = Vec::with_capacity(triplets.len() + 2);
+    lines.push("%%MatrixMarket matrix coordinate integer general".into());
+    lines.push(format!("{} {} {}", nrow, ncol, triplets.len()).into_boxed_str());
+
+    for &(i, j, x) in triplets {
+        lines.push(format!("{} {} {}", i + 1, j + 1, x.round() as i64).into_boxed_str());
+    }
+
+    write_lines(&lines, output_file)
+}
+
+/// Compress sparse triplets by column, as in `Matrix::dgCMatrix`.
+/// Returns 0-based row indices `i`, column pointers `p` (of length
+/// `ncol + 1`) and values `x`, ordered by column and then by row.
+///
+/// * `triplets` - 0-based (row, column, value) triplets
+/// * `ncol` - number of columns
+///
+#[allow(dead_code)]
+pub fn triplets_to_csc(
+    triplets: &[(usize, usize, f32)],
+    ncol: usize,
+) -> (Vec<usize>, Vec<usize>, Vec<f32>) {
+    let mut sorted: Vec<&(usize, usize, f32)> = triplets.iter().collect();
+    sorted.sort_by_key(|&&(i, j, _)| (j, i));
+
+    let mut p = vec![0; ncol + 1];
+    for &&(_, j, _) in sorted.iter() {
+        p[j + 1] += 1;
+    }
+    let mut nnz = 0;
+    for pj in p.iter_mut() {
+        nnz += *pj;
+        *pj = nnz;
+    }
+
+    let i = sorted.iter().map(|&&(i, _, _)| i).collect();
+    let x = sorted.iter().map(|&&(_, _, x)| x).collect();
+    (i, p, x)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn triplets_to_csc_sorts_and_skips_empty_columns() {
+        // 3 x 5 matrix with empty columns 0, 2 and 4
+        //   . 1 . 4 .
+        //   . . . . .
+        //   . 2 . 3 .
+        let triplets = [(2, 3, 3_f32), (0, 1, 1_f32), (0, 3, 4_f32), (2, 1, 2_f32)];
+        let (i, p, x) = triplets_to_csc(&triplets, 5);
+        assert_eq!(i, vec![0, 2, 0, 2]);
+        assert_eq!(p, vec![0, 0, 2, 2, 4, 4]);
+        assert_eq!(x, vec![1_f32, 2_f32, 4_f32, 3_f32]);
+
+        let (i, p, x) = triplets_to_csc(&[], 3);
+        assert!(i.is_empty() && x.is_empty());
+        assert_eq!(p, vec![0, 0, 0, 0]);
+    }
+}
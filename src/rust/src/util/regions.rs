@@ -0,0 +1,187 @@
+use crate::util::file::read_lines;
+use crate::util::gff::read_gff_features;
+
+use clap::Args;
+use std::collections::{HashMap, HashSet};
+
+const DEFAULT_FEATURE_TYPES: &str = "exon";
+
+/// Intervals to visit on each contig
+pub type ContigIntervals = Vec<(Box<str>, Vec<(i64, i64)>)>;
+
+/// Genomic regions to visit, e.g., a gene panel, instead of sweeping
+/// through all the contigs in the BAM header
+///
+#[derive(Args, Debug, Clone)]
+pub struct RegionFilters {
+    /// BED file of regions to visit (can be gzipped)
+    #[arg(long)]
+    pub bed: Option<Box<str>>,
+    /// Only visit these contigs, e.g., `chr1,chr2`
+    #[arg(long, value_delimiter = ',')]
+    pub contigs: Vec<Box<str>>,
+    /// Skip contigs shorter than this, e.g., scaffolds and decoys
+    #[arg(long, default_value_t = 0)]
+    pub min_contig_len: i64,
+    /// GFF/GTF file whose features are the regions to visit
+    #[arg(long)]
+    pub gff: Option<Box<str>>,
+    /// GFF feature types to take the regions from
+    #[arg(long, value_delimiter = ',', default_value = DEFAULT_FEATURE_TYPES)]
+    pub feature_types: Vec<Box<str>>,
+}
+
+impl Default for RegionFilters {
+    fn default() -> Self {
+        RegionFilters {
+            bed: None,
+            contigs: vec![],
+            min_contig_len: 0,
+            gff: None,
+            feature_types: vec![DEFAULT_FEATURE_TYPES.into()],
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl RegionFilters {
+    /// Select the regions to visit within the contigs.  Contigs are
+    /// kept in the given order if they are listed (or no list is
+    /// given) and long enough.  Without BED or GFF files, the whole
+    /// contigs are visited; otherwise, only their intervals (merged
+    /// and clipped to the contig lengths).
+    ///
+    /// Listed contigs must be in `contigs`, while those only in the
+    /// BED or GFF files are skipped with a warning.  Fails if nothing
+    /// is left to visit.
+    ///
+    /// * `contigs` - contig names and lengths, e.g., in the BAM header
+    ///
+    /// Returns 0-based, half-open intervals sorted within each contig
+    ///
+    pub fn select(&self, contigs: &[(Box<str>, i64)]) -> anyhow::Result<ContigIntervals> {
+        let known: HashSet<&str> = contigs.iter().map(|(chr, _)| chr.as_ref()).collect();
+        let listed: HashSet<&str> = self.contigs.iter().map(|x| x.as_ref()).collect();
+
+        let unknown: Vec<&str> = self
+            .contigs
+            .iter()
+            .map(|x| x.as_ref())
+            .filter(|x| !known.contains(x))
+            .collect();
+        if !unknown.is_empty() {
+            return Err(anyhow::anyhow!(
+                "contigs not found in the BAM files: {}",
+                unknown.join(",")
+            ));
+        }
+
+        let contigs = contigs.iter().filter(|(chr, len)| {
+            (listed.is_empty() || listed.contains(chr.as_ref())) && *len >= self.min_contig_len
+        });
+
+        if self.bed.is_none() && self.gff.is_none() {
+            let ret: ContigIntervals = contigs
+                .filter(|(_, len)| *len > 0)
+                .map(|(chr, len)| (chr.clone(), vec![(0, *len)]))
+                .collect();
+            return non_empty(ret);
+        }
+
+        let mut intervals: HashMap<Box<str>, Vec<(i64, i64)>> = HashMap::new();
+
+        if let Some(bed_file) = self.bed.as_deref() {
+            for (chr, lb, ub) in read_bed(bed_file)? {
+                intervals.entry(chr).or_default().push((lb, ub));
+            }
+        }
+
+        if let Some(gff_file) = self.gff.as_deref() {
+            for f in read_gff_features(gff_file, &self.feature_types, "gene_id")? {
+                intervals.entry(f.chr).or_default().extend(f.intervals);
+            }
+        }
+
+        let mut unknown: Vec<&str> = intervals
+            .keys()
+            .map(|x| x.as_ref())
+            .filter(|x| !known.contains(x))
+            .collect();
+        if !unknown.is_empty() {
+            unknown.sort();
+            eprintln!(
+                "warning: skipping regions on contigs not found in the BAM files: {}",
+                unknown.join(",")
+            );
+        }
+
+        let ret: ContigIntervals = contigs
+            .filter_map(|(chr, len)| {
+                let clipped = intervals
+                    .remove(chr)?
+                    .into_iter()
+                    .map(|(lb, ub)| (lb.max(0), ub.min(*len)))
+                    .filter(|(lb, ub)| lb < ub)
+                    .collect();
+                let merged = merge_intervals(clipped);
+                (!merged.is_empty()).then(|| (chr.clone(), merged))
+            })
+            .collect();
+        non_empty(ret)
+    }
+}
+
+/// Fail if there is nothing to visit
+fn non_empty(intervals: ContigIntervals) -> anyhow::Result<ContigIntervals> {
+    if intervals.is_empty() {
+        return Err(anyhow::anyhow!("no regions left to visit"));
+    }
+    Ok(intervals)
+}
+
+/// Read 0-based, half-open intervals `(chr, start, end)` from a BED
+/// file, skipping comments, `track` and `browser` lines
+///
+pub fn read_bed(bed_file: &str) -> anyhow::Result<Vec<(Box<str>, i64, i64)>> {
+    let mut ret = vec![];
+    for line in read_lines(bed_file)? {
+        if line.is_empty()
+            || line.starts_with('#')
+            || line.starts_with("track")
+            || line.starts_with("browser")
+        {
+            continue;
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.len() < 3 {
+            return Err(anyhow::anyhow!(
+                "invalid BED line in {}: {}",
+                bed_file,
+                line
+            ));
+        }
+        let lb: i64 = words[1]
+            .trim()
+            .parse()
+            .map_err(|_| anyhow::anyhow!("invalid BED start in {}: {}", bed_file, line))?;
+        let ub: i64 = words[2]
+            .trim()
+            .parse()
+            .map_err(|_| anyhow::anyhow!("invalid BED end in {}: {}", bed_file, line))?;
+        ret.push((words[0].into(), lb, ub));
+    }
+    Ok(ret)
+}
+
+/// Sort and merge overlapping or adjacent intervals
+fn merge_intervals(mut intervals: Vec<(i64, i64)>) -> Vec<(i64, i64)> {
+    intervals.sort();
+    let mut ret: Vec<(i64, i64)> = Vec::with_capacity(intervals.len());
+    for (lb, ub) in intervals {
+        match ret.last_mut() {
+            Some(last) if lb <= last.1 => last.1 = last.1.max(ub),
+            _ => ret.push((lb, ub)),
+        }
+    }
+    ret
+}
@@ -0,0 +1,238 @@
+use crate::util::bam::*;
+use crate::util::dna::DnaStrand;
+use crate::util::gff::read_gff_features;
+
+use bio::data_structures::interval_tree::ArrayBackedIntervalTree;
+use rust_htslib::bam::{ext::BamRecordExtensions, Read};
+use std::collections::{HashMap, HashSet};
+
+pub const DEFAULT_MAX_READS: usize = 200_000;
+pub const DEFAULT_MIN_AGREEMENT: f32 = 0.75;
+
+/// Read orientations relative to the annotated gene strands, in the
+/// spirit of RSeQC `infer_experiment.py`
+///
+pub struct StrandednessReport {
+    /// the most likely library type
+    pub library_type: LibraryType,
+    /// fraction of reads consistent with the library type (for
+    /// unstranded libraries, the best among the stranded ones)
+    pub agreement: f32,
+    /// number of reads overlapping exons of one strand only
+    pub nreads: usize,
+    /// fraction of reads with cell barcodes
+    pub barcoded: f32,
+    /// read counts by [mate][read on reverse?][gene on reverse?],
+    /// where mate 0: single-end, 1: read 1, 2: read 2
+    pub counts: [[[usize; 2]; 2]; 3],
+}
+
+#[allow(dead_code)]
+impl StrandednessReport {
+    /// Fraction of reads whose transcript strands under this library
+    /// type agree with the gene strands
+    pub fn agreement_of(&self, library_type: LibraryType) -> f32 {
+        let mut agree = 0;
+        for (mate, counts) in self.counts.iter().enumerate() {
+            for (read_rev, counts) in counts.iter().enumerate() {
+                for (gene_rev, n) in counts.iter().enumerate() {
+                    if ((read_rev == 1) ^ library_type.flips(mate)) == (gene_rev == 1) {
+                        agree += n;
+                    }
+                }
+            }
+        }
+        agree as f32 / self.nreads.max(1) as f32
+    }
+
+    /// (mate, read strand, gene strand, count), e.g., ("1", "+", "-",
+    /// 1234), where mate is "1", "2" or "." for single-end reads
+    pub fn count_table(&self) -> Vec<(Box<str>, DnaStrand, DnaStrand, usize)> {
+        let strand = |x: usize| match x {
+            0 => DnaStrand::Forward,
+            _ => DnaStrand::Reverse,
+        };
+        let mut ret = vec![];
+        for (mate, counts) in self.counts.iter().enumerate() {
+            let mate: Box<str> = match mate {
+                0 => ".".into(),
+                _ => mate.to_string().into(),
+            };
+            for (read_rev, counts) in counts.iter().enumerate() {
+                for (gene_rev, &n) in counts.iter().enumerate() {
+                    if n > 0 {
+                        ret.push((mate.clone(), strand(read_rev), strand(gene_rev), n));
+                    }
+                }
+            }
+        }
+        ret
+    }
+}
+
+/// Infer the library type by sampling reads overlapping GFF exons
+/// and comparing their orientations with the gene strands.  Reads
+/// overlapping exons on both strands are ignored.  If reads mostly
+/// carry cell barcodes, 10x library types are considered; otherwise,
+/// the generic fr-firststrand and fr-secondstrand.  The library is
+/// called unstranded unless the best agreement reaches
+/// `min_agreement`.
+///
+/// * `bam_readers` - indexed BAM (or CRAM) readers
+/// * `gff_file` - GFF/GTF file name
+/// * `feature_types` - feature types to take the strands from, e.g., `exon`
+/// * `read_filters` - filters on reads
+/// * `max_reads` - stop after this many informative reads
+/// * `min_agreement` - minimum agreement to call a stranded library
+///
+pub fn infer_library_type(
+    bam_readers: &BamReaderPool,
+    gff_file: &str,
+    feature_types: &[Box<str>],
+    read_filters: &ReadFilters,
+    max_reads: usize,
+    min_agreement: f32,
+) -> anyhow::Result<StrandednessReport> {
+    let features: Vec<_> = read_gff_features(gff_file, feature_types, "gene_id")?
+        .into_iter()
+        .filter(|f| f.strand.is_some())
+        .collect();
+
+    if features.is_empty() {
+        return Err(anyhow::anyhow!("no stranded features in {}", gff_file));
+    }
+
+    let mut trees: HashMap<Box<str>, ArrayBackedIntervalTree<i64, DnaStrand>> = HashMap::new();
+    for f in features.iter() {
+        let Some(strand) = f.strand else {
+            continue;
+        };
+        let tree = trees.entry(f.chr.clone()).or_default();
+        for &(lb, ub) in f.intervals.iter() {
+            tree.insert(lb..ub, strand);
+        }
+    }
+    for tree in trees.values_mut() {
+        tree.index();
+    }
+
+    // spread the sample over all the features
+    let max_reads_per_feature = (max_reads / features.len()).max(10);
+
+    let mut counts = [[[0_usize; 2]; 2]; 3];
+    let mut nreads = 0;
+    let mut nbarcoded = 0;
+    let mut visited: HashSet<(Vec<u8>, usize)> = HashSet::new();
+
+    bam_readers.with_reader(|bam_reader| {
+        // features are sorted by position, so visit them in a strided
+        // order not to take the sample from the first contigs only
+        for i in spread_order(features.len()) {
+            if nreads >= max_reads {
+                break;
+            }
+            let f = &features[i];
+            let Some(tree) = trees.get(&f.chr) else {
+                continue;
+            };
+            // annotations may have more contigs than the alignments
+            if bam_reader.header().tid(f.chr.as_bytes()).is_none() {
+                continue;
+            }
+
+            let mut nfeature = 0;
+            for &(lb, ub) in f.intervals.iter() {
+                if nfeature >= max_reads_per_feature {
+                    break;
+                }
+                bam_reader.fetch((f.chr.as_ref(), lb, ub)).map_err(|e| {
+                    anyhow::anyhow!("unable to fetch {}:{}-{}: {}", f.chr, lb, ub, e)
+                })?;
+
+                for rec in bam_reader.records() {
+                    let rec = rec?;
+                    if nfeature >= max_reads_per_feature {
+                        break;
+                    }
+                    if !read_filters.pass_read(&rec) {
+                        continue;
+                    }
+
+                    let mate = mate_of(&rec);
+                    if !visited.insert((rec.qname().to_vec(), mate)) {
+                        continue;
+                    }
+
+                    let strands: HashSet<DnaStrand> = rec
+                        .aligned_blocks()
+                        .flat_map(|[s, e]| tree.find(s..e))
+                        .map(|x| *x.data())
+                        .collect();
+
+                    let gene_strand = match strands.len() {
+                        1 => strands.into_iter().next(),
+                        _ => None,
+                    };
+                    let Some(gene_strand) = gene_strand else {
+                        continue;
+                    };
+
+                    let gene_rev = (gene_strand == DnaStrand::Reverse) as usize;
+                    counts[mate][rec.is_reverse() as usize][gene_rev] += 1;
+
+                    if matches!(read_filters.sample_of(&rec), Some(BamSample::Barcode(_))) {
+                        nbarcoded += 1;
+                    }
+                    nreads += 1;
+                    nfeature += 1;
+                }
+            }
+        }
+        Ok(())
+    })?;
+
+    if nreads == 0 {
+        return Err(anyhow::anyhow!("no reads found over the features"));
+    }
+
+    let mut ret = StrandednessReport {
+        library_type: LibraryType::Unstranded,
+        agreement: 0_f32,
+        nreads,
+        barcoded: nbarcoded as f32 / nreads as f32,
+        counts,
+    };
+
+    let candidates = match ret.barcoded > 0.5 {
+        true => [LibraryType::TenX3p, LibraryType::TenX5p],
+        _ => [LibraryType::FrSecondstrand, LibraryType::FrFirststrand],
+    };
+
+    for library_type in candidates {
+        let agreement = ret.agreement_of(library_type);
+        if agreement > ret.agreement {
+            ret.agreement = agreement;
+            if agreement >= min_agreement {
+                ret.library_type = library_type;
+            }
+        }
+    }
+
+    Ok(ret)
+}
+
+/// Indices `0..n` in a strided order, with the stride coprime to `n`
+/// and about 0.618 n, so that any prefix spreads over the whole range
+fn spread_order(n: usize) -> impl Iterator<Item = usize> {
+    let gcd = |mut a: usize, mut b: usize| {
+        while b > 0 {
+            (a, b) = (b, a % b);
+        }
+        a
+    };
+    let mut stride = ((n as f64 * 0.618) as usize).max(1);
+    while n > 1 && gcd(stride, n) != 1 {
+        stride += 1;
+    }
+    (0..n).map(move |i| (i * stride) % n)
+}